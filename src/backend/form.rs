@@ -0,0 +1,1071 @@
+use crate::{error::ServerError, loras, metrics, models, presets, utils::unix_timestamp};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use endpoints::{
+    files::FileObject,
+    images::{ImageCreateRequest, ImageEditRequest, ImageVariationRequest},
+};
+use hyper::{body::to_bytes, header::CONTENT_TYPE, Body, Request, Response};
+use multipart::server::{Multipart, ReadEntry, ReadEntryResult};
+use multipart_2021 as multipart;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::{Cursor, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use thiserror::Error;
+
+/// Error returned while reading the fields of an image request.
+#[derive(Error, Debug)]
+pub(crate) enum FormError {
+    /// The request body cannot be read as a form.
+    #[error("{0}")]
+    InvalidBody(String),
+    /// The content type of the request body is not supported.
+    #[error("{0}")]
+    UnsupportedMediaType(String),
+    /// A field has an invalid value.
+    #[error("Invalid value for the `{name}` field. {reason}")]
+    InvalidField { name: String, reason: String },
+    /// A required field is absent.
+    #[error("The `{0}` field is required.")]
+    MissingField(String),
+    /// A file field refers to a file id that does not exist.
+    #[error("Invalid value for the `{name}` field. The file `{id}` is not found.")]
+    FileNotFound { name: String, id: String },
+    /// The field is not supported by the endpoint.
+    #[error("Unsupported field: {0}")]
+    UnsupportedField(String),
+    /// The server failed to process the request.
+    #[error("{0}")]
+    Internal(String),
+}
+impl FormError {
+    pub(crate) fn invalid_field(name: &str, reason: impl Display) -> Self {
+        FormError::InvalidField {
+            name: name.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Build the error response.
+    pub(crate) fn into_response(self) -> Response<Body> {
+        // log
+        error!(target: "stdout", "{}", &self);
+
+        ServerError::from(self).into_response()
+    }
+}
+impl From<FormError> for ServerError {
+    fn from(e: FormError) -> Self {
+        let message = e.to_string();
+
+        match e {
+            FormError::InvalidBody(_) => ServerError::InvalidRequest {
+                message,
+                param: None,
+                code: None,
+            },
+            FormError::UnsupportedMediaType(_) => ServerError::UnsupportedMediaType(message),
+            FormError::InvalidField { name, .. } => ServerError::InvalidRequest {
+                message,
+                param: Some(name),
+                code: Some("invalid_value".to_string()),
+            },
+            FormError::MissingField(name) => ServerError::InvalidRequest {
+                message,
+                param: Some(name),
+                code: Some("missing_required_parameter".to_string()),
+            },
+            FormError::UnsupportedField(name) => ServerError::InvalidRequest {
+                message,
+                param: Some(name),
+                code: Some("unknown_parameter".to_string()),
+            },
+            FormError::FileNotFound { name, .. } => ServerError::NotFound {
+                message,
+                param: Some(name),
+                code: Some("file_not_found".to_string()),
+            },
+            FormError::Internal(_) => ServerError::Operation(message),
+        }
+    }
+}
+
+/// Value of a field in an image request.
+#[derive(Debug)]
+pub(crate) enum FieldValue {
+    /// A text field.
+    Text(String),
+    /// An uploaded file, stored in the `archives` directory once the field is used.
+    Upload { filename: String, data: Vec<u8> },
+    /// A JSON array or object.
+    Json(serde_json::Value),
+    /// A file already stored in the `archives` directory.
+    Stored(FileObject),
+}
+impl FieldValue {
+    /// Return the text of a text field.
+    pub(crate) fn text(self, name: &str) -> Result<String, FormError> {
+        match self {
+            FieldValue::Text(text) => Ok(text),
+            FieldValue::Upload { .. } | FieldValue::Stored(_) => Err(FormError::invalid_field(
+                name,
+                "The field should be a text field.",
+            )),
+            FieldValue::Json(_) => Err(FormError::invalid_field(
+                name,
+                "The field should be a string, a number or a boolean.",
+            )),
+        }
+    }
+
+    /// Deserialize a JSON field. In a multipart form, the field is a text field holding JSON.
+    pub(crate) fn deserialize<T: DeserializeOwned>(self, name: &str) -> Result<T, FormError> {
+        let result = match self {
+            FieldValue::Json(value) => serde_json::from_value(value),
+            FieldValue::Text(text) => serde_json::from_str(&text),
+            FieldValue::Upload { .. } | FieldValue::Stored(_) => {
+                return Err(FormError::invalid_field(
+                    name,
+                    "The field should be a JSON text field.",
+                ));
+            }
+        };
+
+        result.map_err(|e| FormError::invalid_field(name, e))
+    }
+
+    /// Parse the text of a text field.
+    pub(crate) fn parse<T>(self, name: &str) -> Result<T, FormError>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let text = self.text(name)?;
+
+        text.trim()
+            .parse::<T>()
+            .map_err(|e| FormError::invalid_field(name, e))
+    }
+
+    /// Return the file of a file field.
+    ///
+    /// An uploaded file or a base64 data URL is stored in the `archives` directory, while a file
+    /// id refers to a file stored before.
+    pub(crate) fn file(self, name: &str) -> Result<FileObject, FormError> {
+        match self {
+            FieldValue::Stored(file_object) => Ok(file_object),
+            FieldValue::Upload { filename, data } => save_file(&filename, &data),
+            FieldValue::Text(text) if text.starts_with("data:") => {
+                let (filename, data) = decode_data_url(name, &text)?;
+                save_file(&filename, &data)
+            }
+            FieldValue::Text(text) if text.trim().starts_with("file_") => {
+                // the id names a directory in `archives`, so it is checked before any lookup
+                let id = text.trim();
+                check_file_id(name, id)?;
+
+                retrieve_file(name, id)
+            }
+            _ => Err(FormError::invalid_field(
+                name,
+                "The field should be a file upload, a base64 data URL or a file id.",
+            )),
+        }
+    }
+
+    /// Whether [`FieldValue::file`] stores a new file for the value.
+    fn stores_file(&self) -> bool {
+        match self {
+            FieldValue::Upload { .. } => true,
+            FieldValue::Text(text) => text.starts_with("data:"),
+            _ => false,
+        }
+    }
+}
+
+/// Files stored in the `archives` directory while reading an image request.
+///
+/// The files are removed when the value is dropped, so that a request rejected after its files
+/// are stored leaves nothing behind, unless the request is accepted with [`Uploads::keep`].
+#[derive(Debug, Default)]
+pub(crate) struct Uploads {
+    ids: Vec<String>,
+}
+impl Uploads {
+    /// Return the file of a file field, recording the file if it is newly stored.
+    pub(crate) fn store(&mut self, name: &str, value: FieldValue) -> Result<FileObject, FormError> {
+        let stores_file = value.stores_file();
+
+        let file_object = value.file(name)?;
+        if stores_file {
+            metrics::observe_upload(file_object.bytes);
+            self.ids.push(file_object.id.clone());
+        }
+
+        Ok(file_object)
+    }
+
+    /// Keep the stored files, once the request is accepted.
+    pub(crate) fn keep(mut self) {
+        self.ids.clear();
+    }
+}
+impl Drop for Uploads {
+    fn drop(&mut self) {
+        for id in self.ids.iter() {
+            match fs::remove_dir_all(archives_dir().join(id)) {
+                Ok(()) => {
+                    info!(target: "stdout", "Removed the file {} of the rejected request.", id)
+                }
+                Err(e) => {
+                    // log
+                    error!(target: "stdout", "Failed to remove the file {} of the rejected request. {}", id, e);
+                }
+            }
+        }
+    }
+}
+
+/// Fields of an image request, in the order they were sent.
+#[derive(Debug, Default)]
+pub(crate) struct Form {
+    fields: Vec<(String, FieldValue)>,
+}
+impl Form {
+    /// Read the fields of a `multipart/form-data` request body.
+    pub(crate) fn from_multipart(boundary: &str, body: &[u8]) -> Result<Self, FormError> {
+        let mut multipart = Multipart::with_body(Cursor::new(body), boundary);
+
+        let mut form = Form::default();
+        loop {
+            let mut field = match multipart.read_entry_mut() {
+                ReadEntryResult::Entry(field) => field,
+                ReadEntryResult::End(_) => break,
+                ReadEntryResult::Error(_, e) => {
+                    return Err(FormError::InvalidBody(format!(
+                        "Failed to read the multipart body. {}",
+                        e
+                    )));
+                }
+            };
+
+            let name = field.headers.name.to_string();
+
+            let mut data = Vec::new();
+            if let Err(e) = field.data.read_to_end(&mut data) {
+                return Err(FormError::InvalidBody(format!(
+                    "Failed to read the `{}` field. {}",
+                    name, e
+                )));
+            }
+
+            let value = match field.headers.filename.take() {
+                Some(filename) => FieldValue::Upload { filename, data },
+                None => match String::from_utf8(data) {
+                    Ok(text) => FieldValue::Text(text),
+                    Err(_) => {
+                        return Err(FormError::invalid_field(
+                            &name,
+                            "The field should be a UTF-8 text field, or a file field with a filename.",
+                        ));
+                    }
+                },
+            };
+
+            form.fields.push((name, value));
+        }
+
+        Ok(form)
+    }
+
+    /// Read the fields of a JSON object. Null fields are skipped.
+    pub(crate) fn from_json(value: serde_json::Value) -> Result<Self, FormError> {
+        let map = match value {
+            serde_json::Value::Object(map) => map,
+            _ => {
+                return Err(FormError::InvalidBody(
+                    "The request body should be a JSON object.".to_string(),
+                ));
+            }
+        };
+
+        let mut form = Form::default();
+        for (name, value) in map {
+            let value = match value {
+                serde_json::Value::Null => continue,
+                serde_json::Value::String(text) => FieldValue::Text(text),
+                serde_json::Value::Bool(b) => FieldValue::Text(b.to_string()),
+                serde_json::Value::Number(n) => FieldValue::Text(n.to_string()),
+                value => FieldValue::Json(value),
+            };
+
+            form.fields.push((name, value));
+        }
+
+        Ok(form)
+    }
+
+    /// Remove the field from the form and return its last value.
+    pub(crate) fn take(&mut self, name: &str) -> Option<FieldValue> {
+        let mut value = None;
+        let mut i = 0;
+        while i < self.fields.len() {
+            if self.fields[i].0 == name {
+                value = Some(self.fields.remove(i).1);
+            } else {
+                i += 1;
+            }
+        }
+
+        value
+    }
+
+    /// Return the last value of the text field, leaving it in the form.
+    pub(crate) fn text(&self, name: &str) -> Option<String> {
+        self.fields
+            .iter()
+            .rev()
+            .find_map(|(field, value)| match value {
+                FieldValue::Text(text) if field == name => Some(text.clone()),
+                _ => None,
+            })
+    }
+
+    /// Remove the `loras` field from the form and return the LoRA models it lists.
+    pub(crate) fn take_loras(&mut self) -> Result<Option<Vec<LoraRef>>, FormError> {
+        self.take("loras")
+            .map(|value| value.deserialize("loras"))
+            .transpose()
+    }
+
+    /// Remove the boolean field from the form and return its value.
+    pub(crate) fn take_bool(&mut self, name: &str) -> Result<Option<bool>, FormError> {
+        self.take(name).map(|value| value.parse(name)).transpose()
+    }
+
+    /// Add the parameters of the preset named by the `preset` field, then the default generation
+    /// parameters of the served model, for the fields absent from the form.
+    pub(crate) fn apply_defaults(&mut self, edit: bool) -> Result<(), FormError> {
+        let preset = self
+            .take("preset")
+            .map(|value| value.text("preset"))
+            .transpose()?;
+
+        let defaults = request_defaults(preset.as_deref(), edit, |name| {
+            self.fields.iter().any(|(field, _)| field == name)
+        })?;
+        for (name, value) in defaults {
+            let value = match value {
+                serde_json::Value::String(text) => FieldValue::Text(text),
+                value => FieldValue::Text(value.to_string()),
+            };
+
+            self.fields.push((name, value));
+        }
+
+        Ok(())
+    }
+
+    /// Build the image request from the fields.
+    ///
+    /// The files to store are stored last, once every other field is valid, and they are removed
+    /// when the returned [`Uploads`] is dropped, unless the request is accepted.
+    pub(crate) fn extract<R: FormRequest>(self) -> Result<(R, Uploads), FormError> {
+        let mut request = R::default();
+
+        let mut files = Vec::new();
+        for (name, value) in self.fields {
+            match R::FILE_FIELDS.contains(&name.as_str()) && value.stores_file() {
+                true => files.push((name, value)),
+                false => request.set_field(&name, value)?,
+            }
+        }
+
+        let mut uploads = Uploads::default();
+        for (name, value) in files {
+            let file_object = uploads.store(&name, value)?;
+            request.set_field(&name, FieldValue::Stored(file_object))?;
+        }
+
+        request.check_required()?;
+
+        Ok((request, uploads))
+    }
+}
+
+/// Read the form of a `multipart/form-data` or `application/json` request.
+pub(crate) async fn read_form(req: Request<Body>) -> Result<Form, FormError> {
+    let content_type = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .unwrap_or("application/json")
+        .to_lowercase();
+
+    if content_type.starts_with("multipart/") {
+        read_multipart(req).await
+    } else if content_type.starts_with("application/json") {
+        read_json(req).await
+    } else {
+        Err(FormError::UnsupportedMediaType(format!(
+            "Unsupported content type: {}. The request body should be `multipart/form-data` or `application/json`.",
+            content_type
+        )))
+    }
+}
+
+/// Read the form of an `application/json` request.
+pub(crate) async fn read_json(req: Request<Body>) -> Result<Form, FormError> {
+    let body_bytes = match to_bytes(req.into_body()).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            return Err(FormError::InvalidBody(format!(
+                "Fail to read buffer from request body. {}",
+                e
+            )));
+        }
+    };
+
+    match serde_json::from_slice(&body_bytes) {
+        Ok(value) => Form::from_json(value),
+        Err(e) => Err(FormError::InvalidBody(format!(
+            "Fail to deserialize the request body. {}",
+            e
+        ))),
+    }
+}
+
+/// Read the form of a `multipart/form-data` request.
+pub(crate) async fn read_multipart(req: Request<Body>) -> Result<Form, FormError> {
+    let content_type = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .unwrap_or_default();
+    if !content_type.to_lowercase().starts_with("multipart/") {
+        return Err(FormError::UnsupportedMediaType(format!(
+            "Unsupported content type: {}. The request body should be `multipart/form-data`.",
+            content_type
+        )));
+    }
+
+    let boundary = "boundary=";
+
+    let boundary = req.headers().get(CONTENT_TYPE).and_then(|ct| {
+        let ct = ct.to_str().ok()?;
+        let idx = ct.find(boundary)?;
+        Some(ct[idx + boundary.len()..].trim_matches('"').to_string())
+    });
+    let boundary = match boundary {
+        Some(boundary) => boundary,
+        None => {
+            return Err(FormError::InvalidBody(
+                "The request body should be `multipart/form-data` with a boundary.".to_string(),
+            ));
+        }
+    };
+
+    let body_bytes = match to_bytes(req.into_body()).await {
+        Ok(body_bytes) => body_bytes,
+        Err(e) => {
+            return Err(FormError::InvalidBody(format!(
+                "Fail to read buffer from request body. {}",
+                e
+            )));
+        }
+    };
+
+    Form::from_multipart(&boundary, &body_bytes)
+}
+
+/// An image request built field by field.
+pub(crate) trait FormRequest: Default {
+    /// The file fields, whose uploads are stored in the `archives` directory.
+    const FILE_FIELDS: &'static [&'static str];
+
+    /// Set the field of the request.
+    fn set_field(&mut self, name: &str, value: FieldValue) -> Result<(), FormError>;
+
+    /// Check that the required fields are set.
+    fn check_required(&self) -> Result<(), FormError>;
+}
+
+impl FormRequest for ImageCreateRequest {
+    const FILE_FIELDS: &'static [&'static str] = &["control_image"];
+
+    fn set_field(&mut self, name: &str, value: FieldValue) -> Result<(), FormError> {
+        match name {
+            "prompt" => self.prompt = value.text(name)?,
+            "negative_prompt" => self.negative_prompt = Some(value.text(name)?),
+            "model" => self.model = value.text(name)?,
+            "n" => self.n = Some(value.parse(name)?),
+            "size" => {
+                let (height, width) = parse_size(name, value)?;
+                self.height = Some(height);
+                self.width = Some(width);
+            }
+            "response_format" => self.response_format = Some(value.parse(name)?),
+            "user" => self.user = Some(value.text(name)?),
+            "cfg_scale" => self.cfg_scale = Some(value.parse(name)?),
+            "sample_method" => self.sample_method = Some(value.text(name)?.trim().into()),
+            "steps" => self.steps = Some(value.parse(name)?),
+            "height" => self.height = Some(value.parse(name)?),
+            "width" => self.width = Some(value.parse(name)?),
+            "control_strength" => self.control_strength = Some(value.parse(name)?),
+            "control_image" => self.control_image = Some(value.file(name)?),
+            "seed" => self.seed = Some(value.parse(name)?),
+            unsupported_field => return Err(FormError::UnsupportedField(unsupported_field.into())),
+        }
+
+        Ok(())
+    }
+
+    fn check_required(&self) -> Result<(), FormError> {
+        if self.prompt.is_empty() {
+            return Err(FormError::MissingField("prompt".into()));
+        }
+
+        Ok(())
+    }
+}
+
+impl FormRequest for ImageEditRequest {
+    const FILE_FIELDS: &'static [&'static str] = &["image", "mask", "control_image"];
+
+    fn set_field(&mut self, name: &str, value: FieldValue) -> Result<(), FormError> {
+        match name {
+            "image" => self.image = value.file(name)?,
+            "prompt" => self.prompt = value.text(name)?,
+            "negative_prompt" => self.negative_prompt = Some(value.text(name)?),
+            "mask" => self.mask = Some(value.file(name)?),
+            "model" => self.model = value.text(name)?,
+            "n" => self.n = Some(value.parse(name)?),
+            "size" => {
+                let (height, width) = parse_size(name, value)?;
+                self.height = Some(height);
+                self.width = Some(width);
+            }
+            "response_format" => self.response_format = Some(value.parse(name)?),
+            "user" => self.user = Some(value.text(name)?),
+            "cfg_scale" => self.cfg_scale = Some(value.parse(name)?),
+            "sample_method" => self.sample_method = Some(value.text(name)?.trim().into()),
+            "steps" => self.steps = Some(value.parse(name)?),
+            "height" => self.height = Some(value.parse(name)?),
+            "width" => self.width = Some(value.parse(name)?),
+            "control_strength" => self.control_strength = Some(value.parse(name)?),
+            "control_image" => self.control_image = Some(value.file(name)?),
+            "seed" => self.seed = Some(value.parse(name)?),
+            "strength" => self.strength = Some(value.parse(name)?),
+            unsupported_field => return Err(FormError::UnsupportedField(unsupported_field.into())),
+        }
+
+        Ok(())
+    }
+
+    fn check_required(&self) -> Result<(), FormError> {
+        if self.image.id.is_empty() {
+            return Err(FormError::MissingField("image".into()));
+        }
+        if self.prompt.is_empty() {
+            return Err(FormError::MissingField("prompt".into()));
+        }
+
+        Ok(())
+    }
+}
+
+impl FormRequest for ImageVariationRequest {
+    const FILE_FIELDS: &'static [&'static str] = &["image"];
+
+    fn set_field(&mut self, name: &str, value: FieldValue) -> Result<(), FormError> {
+        match name {
+            "image" => self.image = value.file(name)?,
+            "model" => self.model = value.text(name)?,
+            "n" => self.n = Some(value.parse(name)?),
+            "response_format" => self.response_format = Some(value.parse(name)?),
+            "size" => self.size = Some(value.text(name)?),
+            "user" => self.user = Some(value.text(name)?),
+            unsupported_field => return Err(FormError::UnsupportedField(unsupported_field.into())),
+        }
+
+        Ok(())
+    }
+
+    fn check_required(&self) -> Result<(), FormError> {
+        if self.image.id.is_empty() {
+            return Err(FormError::MissingField("image".into()));
+        }
+
+        Ok(())
+    }
+}
+
+/// A file uploaded with `POST /v1/files`.
+#[derive(Debug, Default)]
+pub(crate) struct FileUploadRequest {
+    /// The name and the content of the uploaded file.
+    pub(crate) file: Option<(String, Vec<u8>)>,
+    /// The intended purpose of the uploaded file.
+    pub(crate) purpose: String,
+}
+impl FileUploadRequest {
+    /// Store the uploaded file in the `archives` directory.
+    pub(crate) fn save(self) -> Result<FileObject, FormError> {
+        let (filename, data) = match self.file {
+            Some(file) => file,
+            None => return Err(FormError::MissingField("file".into())),
+        };
+
+        let mut file_object = save_file(&filename, &data)?;
+        file_object.purpose = self.purpose;
+
+        Ok(file_object)
+    }
+}
+
+impl FormRequest for FileUploadRequest {
+    // the uploaded file is stored by `FileUploadRequest::save`
+    const FILE_FIELDS: &'static [&'static str] = &[];
+
+    fn set_field(&mut self, name: &str, value: FieldValue) -> Result<(), FormError> {
+        match name {
+            "file" => match value {
+                FieldValue::Upload { filename, data } => self.file = Some((filename, data)),
+                _ => {
+                    return Err(FormError::invalid_field(
+                        name,
+                        "The field should be a file upload.",
+                    ));
+                }
+            },
+            "purpose" => self.purpose = value.text(name)?,
+            unsupported_field => return Err(FormError::UnsupportedField(unsupported_field.into())),
+        }
+
+        Ok(())
+    }
+
+    fn check_required(&self) -> Result<(), FormError> {
+        if self.file.is_none() {
+            return Err(FormError::MissingField("file".into()));
+        }
+        if self.purpose.trim().is_empty() {
+            return Err(FormError::MissingField("purpose".into()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Add the parameters of the preset named by the `preset` field, then the default generation
+/// parameters of the served model, for the fields absent from, or null in, a JSON image request.
+pub(crate) fn apply_json_defaults(
+    value: &mut serde_json::Value,
+    edit: bool,
+) -> Result<(), FormError> {
+    let map = match value.as_object_mut() {
+        Some(map) => map,
+        None => return Ok(()),
+    };
+
+    let preset = match map.remove("preset") {
+        Some(serde_json::Value::String(preset)) => Some(preset),
+        Some(serde_json::Value::Null) | None => None,
+        Some(_) => {
+            return Err(FormError::invalid_field(
+                "preset",
+                "The field should be a string.",
+            ));
+        }
+    };
+
+    let defaults = request_defaults(preset.as_deref(), edit, |name| {
+        map.get(name).map(|value| !value.is_null()).unwrap_or(false)
+    })?;
+    for (name, value) in defaults {
+        map.insert(name, value);
+    }
+
+    Ok(())
+}
+
+/// Return the parameters of the preset, falling back on the default generation parameters of the
+/// served model, for the fields the request does not set. The `height` and `width` parameters are
+/// skipped when the request sets the `size` field, and the `strength` parameter only applies to
+/// image edits.
+fn request_defaults(
+    preset: Option<&str>,
+    edit: bool,
+    is_set: impl Fn(&str) -> bool,
+) -> Result<Vec<(String, serde_json::Value)>, FormError> {
+    let mut fields = match models::generation_defaults() {
+        Some(defaults) => defaults.to_fields(),
+        None => serde_json::Map::new(),
+    };
+
+    if let Some(preset) = preset {
+        match presets::preset(preset) {
+            Some(parameters) => fields.extend(parameters.to_fields()),
+            None => {
+                return Err(FormError::invalid_field(
+                    "preset",
+                    format!(
+                        "Unknown preset `{}`. Available presets: {}",
+                        preset,
+                        presets::preset_names().join(", ")
+                    ),
+                ));
+            }
+        }
+    }
+
+    Ok(fields
+        .into_iter()
+        .filter(|(name, _)| match name.as_str() {
+            "strength" => edit && !is_set(name),
+            "height" | "width" => !is_set(name) && !is_set("size"),
+            _ => !is_set(name),
+        })
+        .collect())
+}
+
+/// A LoRA model applied to an image request.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LoraRef {
+    /// The LoRA name, i.e. the file name without extension in the lora model directory.
+    pub(crate) name: String,
+    /// The weight of the LoRA model.
+    #[serde(default = "default_lora_weight")]
+    pub(crate) weight: f32,
+}
+
+fn default_lora_weight() -> f32 {
+    1.0
+}
+
+/// Validate the LoRA models against the lora model directory of the served model, and append
+/// their `<lora:name:weight>` tags to the prompt, the syntax the backend understands.
+pub(crate) fn apply_loras(prompt: &mut String, loras: &[LoraRef]) -> Result<(), FormError> {
+    if loras.is_empty() {
+        return Ok(());
+    }
+
+    let lora_model_dir = match models::lora_model_dir() {
+        Some(lora_model_dir) => lora_model_dir,
+        None => {
+            return Err(FormError::invalid_field(
+                "loras",
+                "The model has no lora model directory. Set the `--lora-model-dir` option to use LoRA models.",
+            ));
+        }
+    };
+    let available = loras::lora_names(&lora_model_dir);
+
+    let mut tags = Vec::with_capacity(loras.len());
+    for lora in loras {
+        if !available.contains(&lora.name) {
+            return Err(FormError::invalid_field(
+                "loras",
+                format!(
+                    "Unknown LoRA model `{}`. Available LoRA models: {}",
+                    lora.name,
+                    available.join(", ")
+                ),
+            ));
+        }
+
+        if !lora.weight.is_finite() {
+            return Err(FormError::invalid_field(
+                "loras",
+                format!(
+                    "The weight of the LoRA model `{}` should be a finite number.",
+                    lora.name
+                ),
+            ));
+        }
+
+        tags.push(format!("<lora:{}:{}>", lora.name, lora.weight));
+    }
+
+    // log
+    info!(target: "stdout", "lora tags: {}", tags.join(""));
+
+    prompt.push_str(&tags.join(""));
+
+    Ok(())
+}
+
+/// Parse the `HeightxWidth` size field, e.g. `512x512`.
+pub(crate) fn parse_size(name: &str, value: FieldValue) -> Result<(usize, usize), FormError> {
+    let size = value.text(name)?;
+
+    let err = || {
+        FormError::invalid_field(
+            name,
+            "The correct format is `HeightxWidth`. Example: 256x256",
+        )
+    };
+
+    let (height, width) = size.trim().split_once('x').ok_or_else(err)?;
+    let height = height.parse().map_err(|_| err())?;
+    let width = width.parse().map_err(|_| err())?;
+
+    Ok((height, width))
+}
+
+/// Look up a file stored before by its id, e.g. `file_4bb32fc4-7c3d-4d42-b8b5-d39e2a0f1c0e`,
+/// checked with [`check_file_id`].
+fn retrieve_file(name: &str, id: &str) -> Result<FileObject, FormError> {
+    match llama_core::files::retrieve_file(id) {
+        Ok(file_object) => {
+            // log
+            info!(target: "stdout", "reuse the file {} for the `{}` field", id, name);
+
+            Ok(file_object)
+        }
+        Err(e) => Err(FormError::Internal(format!(
+            "Failed to retrieve the file `{}`. {}",
+            id, e
+        ))),
+    }
+}
+
+/// Directory of the stored files, shared with the images generated by the backend.
+#[cfg(not(test))]
+fn archives_dir() -> PathBuf {
+    PathBuf::from("archives")
+}
+
+/// Directory of the files stored by the tests, kept apart from the `archives` of the server.
+#[cfg(test)]
+fn archives_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("sd-api-server-test-{}", std::process::id()))
+}
+
+/// Check that the file id refers to a stored file.
+pub(crate) fn check_file_id(name: &str, id: &str) -> Result<(), FormError> {
+    // the id names a directory in `archives`, so it must not contain any path separator
+    if !id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(FormError::invalid_field(
+            name,
+            format!("`{}` is not a valid file id.", id),
+        ));
+    }
+
+    if !id.starts_with("file_") || !archives_dir().join(id).is_dir() {
+        return Err(FormError::FileNotFound {
+            name: name.to_string(),
+            id: id.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Decode a base64 data URL, e.g. `data:image/png;base64,iVBORw0KGgo...`, into a filename
+/// named after the field and the file content.
+fn decode_data_url(name: &str, url: &str) -> Result<(String, Vec<u8>), FormError> {
+    let (meta, payload) = match url.trim_start_matches("data:").split_once(',') {
+        Some((meta, payload)) => (meta, payload),
+        None => {
+            return Err(FormError::invalid_field(
+                name,
+                "The data URL should be `data:<mime type>;base64,<data>`.",
+            ));
+        }
+    };
+
+    let mime_type = match meta.strip_suffix(";base64") {
+        Some(mime_type) => mime_type,
+        None => {
+            return Err(FormError::invalid_field(
+                name,
+                "The data URL should be base64 encoded.",
+            ));
+        }
+    };
+
+    let extension = match mime_type.strip_prefix("image/") {
+        Some("jpeg") => "jpg",
+        Some(subtype)
+            if !subtype.is_empty() && subtype.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            subtype
+        }
+        _ => {
+            return Err(FormError::invalid_field(
+                name,
+                format!("The data URL should contain an image, not `{}`.", mime_type),
+            ));
+        }
+    };
+
+    match STANDARD.decode(payload.trim()) {
+        Ok(data) => Ok((format!("{}.{}", name, extension), data)),
+        Err(e) => Err(FormError::invalid_field(
+            name,
+            format!("Failed to decode the base64 data. {}", e),
+        )),
+    }
+}
+
+/// Store the uploaded file in the `archives` directory.
+pub(crate) fn save_file(filename: &str, data: &[u8]) -> Result<FileObject, FormError> {
+    // keep the file in its archive directory, whatever path the client sent
+    let filename = match Path::new(filename)
+        .file_name()
+        .and_then(|name| name.to_str())
+    {
+        Some(filename) => filename.to_string(),
+        None => {
+            return Err(FormError::InvalidBody(format!(
+                "Invalid filename of the uploaded file: {}",
+                filename
+            )));
+        }
+    };
+
+    // create a file id for the file
+    let id = format!("file_{}", uuid::Uuid::new_v4());
+
+    // save the file
+    let file_path = archives_dir().join(&id);
+    if let Err(e) = fs::create_dir_all(&file_path) {
+        return Err(FormError::Internal(format!(
+            "Failed to create the archive directory {}. {}",
+            file_path.display(),
+            e
+        )));
+    }
+    let mut file = match File::create(file_path.join(&filename)) {
+        Ok(file) => file,
+        Err(e) => {
+            return Err(FormError::Internal(format!(
+                "Failed to create archive document {}. {}",
+                &filename, e
+            )));
+        }
+    };
+    if let Err(e) = file.write_all(data) {
+        return Err(FormError::Internal(format!(
+            "Failed to write archive document {}. {}",
+            &filename, e
+        )));
+    }
+
+    // log
+    info!(target: "stdout", "file_id: {}, file_name: {}, size in bytes: {}", &id, &filename, data.len());
+
+    Ok(FileObject {
+        id,
+        bytes: data.len() as u64,
+        created_at: unix_timestamp(),
+        filename,
+        object: "file".to_string(),
+        purpose: "assistants".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn archive_ids() -> BTreeSet<String> {
+        match fs::read_dir(archives_dir()) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect(),
+            Err(_) => BTreeSet::new(),
+        }
+    }
+
+    fn edit_form(mask: &str) -> Form {
+        Form {
+            fields: vec![
+                (
+                    "image".to_string(),
+                    FieldValue::Upload {
+                        filename: "image.png".to_string(),
+                        data: vec![1, 2, 3],
+                    },
+                ),
+                ("mask".to_string(), FieldValue::Text(mask.to_string())),
+                ("prompt".to_string(), FieldValue::Text("a cat".to_string())),
+            ],
+        }
+    }
+
+    #[test]
+    fn stored_files_are_removed_unless_the_request_is_kept() {
+        let before = archive_ids();
+
+        // the image is stored, then the mask fails
+        assert!(edit_form("data:image/png;base64,!!!")
+            .extract::<ImageEditRequest>()
+            .is_err());
+        assert_eq!(archive_ids(), before);
+
+        let (request, uploads) = edit_form("data:image/png;base64,AQID")
+            .extract::<ImageEditRequest>()
+            .unwrap();
+        let mask = request.mask.unwrap();
+        drop(uploads);
+        assert_eq!(archive_ids(), before);
+
+        let (request, uploads) = edit_form("data:image/png;base64,AQID")
+            .extract::<ImageEditRequest>()
+            .unwrap();
+        uploads.keep();
+        assert!(archives_dir().join(&request.image.id).is_dir());
+        assert_ne!(request.image.id, mask.id);
+
+        for id in [request.image.id, request.mask.unwrap().id] {
+            fs::remove_dir_all(archives_dir().join(id)).unwrap();
+        }
+        let _ = fs::remove_dir(archives_dir());
+    }
+
+    #[test]
+    fn file_ids_with_path_components_are_rejected() {
+        for id in [
+            "file_../archives",
+            "file_a/b",
+            "file_a\\b",
+            "..",
+            "file_.",
+            "file_a b",
+        ] {
+            assert!(
+                matches!(
+                    check_file_id("image", id),
+                    Err(FormError::InvalidField { .. })
+                ),
+                "{}",
+                id
+            );
+            assert!(FieldValue::Text(id.to_string()).file("image").is_err());
+        }
+    }
+
+    #[test]
+    fn unknown_file_ids_are_not_found() {
+        assert!(matches!(
+            check_file_id("image", "file_00000000-0000-0000-0000-000000000000"),
+            Err(FormError::FileNotFound { .. })
+        ));
+        assert!(matches!(
+            check_file_id("image", "upload_1234"),
+            Err(FormError::FileNotFound { .. })
+        ));
+    }
+}
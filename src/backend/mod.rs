@@ -1,4 +1,6 @@
+pub(crate) mod form;
 pub(crate) mod sd;
+pub(crate) mod validate;
 
 use crate::error;
 use hyper::{Body, Request, Response};
@@ -10,9 +12,18 @@ pub(crate) async fn handle_sd_request(req: Request<Body>) -> Response<Body> {
         "/v1/images/generations" => sd::image_generation_handler(req).await,
         "/v1/images/edits" => sd::image_edit_handler(req).await,
         "/v1/images/variations" => sd::image_variation_handler(req).await,
+        "/v1/queue" => sd::queue_handler(req).await,
+        "/v1/loras" => sd::loras_handler(req).await,
+        "/v1/usage" => sd::usage_handler(req).await,
         path => {
             if path.starts_with("/v1/files") {
                 sd::files_handler(req).await
+            } else if path.starts_with("/v1/jobs") {
+                sd::jobs_handler(req).await
+            } else if path.starts_with("/v1/models") {
+                sd::models_handler(req).await
+            } else if path.starts_with("/v1/presets") {
+                sd::presets_handler(req).await
             } else {
                 error::invalid_endpoint(path)
             }
@@ -1,18 +1,33 @@
-use crate::{error, utils::gen_image_id, DOWNLOAD_URL_PREFIX};
+use super::{form, validate};
+use crate::{
+    error::{self, ServerError},
+    jobs,
+    keys::{self, KeyInfo},
+    limits, loras, metrics, models, presets, queue,
+    usage::{self, UsageRecord},
+    utils::gen_image_id,
+    DOWNLOAD_URL_PREFIX,
+};
 use endpoints::{
-    files::{DeleteFileStatus, FileObject},
-    images::{ImageCreateRequest, ImageEditRequest, ImageVariationRequest, ResponseFormat},
+    files::DeleteFileStatus,
+    images::{
+        ImageCreateRequest, ImageEditRequest, ImageVariationRequest, ListImagesResponse,
+        ResponseFormat, SamplingMethod,
+    },
+};
+use hyper::{
+    body::to_bytes,
+    header::{HeaderValue, CONTENT_TYPE, RETRY_AFTER},
+    Body, Method, Request, Response,
 };
-use hyper::{body::to_bytes, header::CONTENT_TYPE, Body, Method, Request, Response};
-use multipart::server::{Multipart, ReadEntry, ReadEntryResult};
-use multipart_2021 as multipart;
 use std::{
-    fs::{self, File},
-    io::{Cursor, Read, Write},
-    path::Path,
-    time::SystemTime,
+    future::Future,
+    time::{Duration, Instant},
 };
 
+/// Interval at which the queue position of a streamed request is checked.
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 pub(crate) async fn image_generation_handler(mut req: Request<Body>) -> Response<Body> {
     // log
     info!(target: "stdout", "Handling the coming image generation request");
@@ -38,707 +53,874 @@ pub(crate) async fn image_generation_handler(mut req: Request<Body>) -> Response
         }
     }
 
+    // the key of the request, whose rate limits apply
+    let key_info = req.extensions().get::<KeyInfo>().cloned();
+
+    // check if the client asks to run the generation as a background job
+    let async_mode = query_flag(&req, "async");
+
+    // check if the client asks for the progress events
+    let mut stream_mode = query_flag(&req, "stream");
+
     let content_type = req
         .headers()
         .get(CONTENT_TYPE)
         .and_then(|ct| ct.to_str().ok());
 
-    if let Some(content_type) = content_type {
-        if content_type.starts_with("multipart/") {
-            // Handle multipart request
-            info!(target: "stdout", "Handling multipart request");
-            // Your multipart handling code here
-        } else {
-            // Handle command request
-            info!(target: "stdout", "Handling command request");
-            // Your command handling code here
-        }
-    } else {
-        // Handle request with no Content-Type header
-        info!(target: "stdout", "Handling request with no Content-Type header");
-        // Your handling code here
-    }
+    // LoRA models to apply, translated into prompt tags once the request is parsed
+    let mut lora_refs = Vec::new();
+
+    // the sampling method as sent by the client, checked with the other parameters
+    let sample_method;
+
+    // the files stored for the request, removed if the request is rejected
+    let mut uploads = form::Uploads::default();
 
     let mut image_request = match content_type {
         Some(content_type) if content_type.starts_with("multipart/") => {
-            let boundary = "boundary=";
+            let mut form = match form::read_multipart(req).await {
+                Ok(form) => form,
+                Err(e) => return e.into_response(),
+            };
 
-            let boundary = req.headers().get("content-type").and_then(|ct| {
-                let ct = ct.to_str().ok()?;
-                let idx = ct.find(boundary)?;
-                Some(ct[idx + boundary.len()..].to_string())
-            });
+            // the `stream` field is handled by the server, not by the backend
+            match form.take_bool("stream") {
+                Ok(Some(stream)) => stream_mode = stream,
+                Ok(None) => {}
+                Err(e) => return e.into_response(),
+            }
 
-            let req_body = req.into_body();
-            let body_bytes = match to_bytes(req_body).await {
-                Ok(body_bytes) => body_bytes,
-                Err(e) => {
-                    let err_msg = format!("Fail to read buffer from request body. {}", e);
+            match form.take_loras() {
+                Ok(Some(loras)) => lora_refs = loras,
+                Ok(None) => {}
+                Err(e) => return e.into_response(),
+            }
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+            if let Err(e) = form.apply_defaults(false) {
+                return e.into_response();
+            }
+            sample_method = form.text("sample_method");
 
-                    return error::internal_server_error(err_msg);
+            match form.extract::<ImageCreateRequest>() {
+                Ok((image_request, form_uploads)) => {
+                    uploads = form_uploads;
+                    image_request
                 }
-            };
+                Err(e) => return e.into_response(),
+            }
+        }
+        Some(content_type) if !content_type.to_lowercase().starts_with("application/json") => {
+            let err_msg = format!(
+                "Unsupported content type: {}. The request body should be `multipart/form-data` or `application/json`.",
+                content_type
+            );
 
-            let cursor = Cursor::new(body_bytes.to_vec());
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::unsupported_media_type(err_msg);
+        }
+        _ => {
+            if req.method() == Method::POST {
+                info!(target: "stdout", "Prepare the image generation request.");
 
-            let mut multipart = Multipart::with_body(cursor, boundary.unwrap());
+                // parse request
+                let body_bytes = match to_bytes(req.body_mut()).await {
+                    Ok(body_bytes) => body_bytes,
+                    Err(e) => {
+                        let err_msg = format!("Fail to read buffer from request body. {}", e);
 
-            let mut image_request = ImageCreateRequest::default();
-            while let ReadEntryResult::Entry(mut field) = multipart.read_entry_mut() {
-                match &*field.headers.name {
-                    "prompt" => match field.is_text() {
-                        true => {
-                            let mut prompt = String::new();
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
 
-                            if let Err(e) = field.data.read_to_string(&mut prompt) {
-                                let err_msg = format!("Failed to read the prompt. {}", e);
+                        return error::bad_request(err_msg);
+                    }
+                };
+                let mut value: serde_json::Value = match serde_json::from_slice(&body_bytes) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        let err_msg =
+                            format!("Fail to deserialize image create request: {msg}", msg = e);
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
 
-                                return error::internal_server_error(err_msg);
-                            }
+                        return error::bad_request(err_msg);
+                    }
+                };
 
-                            image_request.prompt = prompt;
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the prompt. The prompt field in the request should be a text field.";
+                // the `stream` field is handled by the server, not by the backend
+                if let Some(stream) = value.as_object_mut().and_then(|map| map.remove("stream")) {
+                    match stream.as_bool() {
+                        Some(stream) => stream_mode = stream,
+                        None => {
+                            let err_msg = "Failed to parse the stream. The stream field in the request should be a boolean.";
 
                             // log
                             error!(target: "stdout", "{}", &err_msg);
 
-                            return error::internal_server_error(err_msg);
+                            return error::bad_request(err_msg);
                         }
-                    },
-                    "negative_prompt" => match field.is_text() {
-                        true => {
-                            let mut negative_prompt = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut negative_prompt) {
-                                let err_msg = format!("Failed to read the prompt. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                    }
+                }
 
-                                return error::internal_server_error(err_msg);
-                            }
+                if let Some(loras) = value.as_object_mut().and_then(|map| map.remove("loras")) {
+                    match form::FieldValue::Json(loras).deserialize("loras") {
+                        Ok(loras) => lora_refs = loras,
+                        Err(e) => return e.into_response(),
+                    }
+                }
 
-                            image_request.prompt = negative_prompt;
+                // a control image given as a base64 data URL or a file id is resolved here,
+                // since the backend only takes a file object
+                let control_image = match value.get("control_image") {
+                    Some(serde_json::Value::String(text)) => {
+                        match uploads.store("control_image", form::FieldValue::Text(text.clone())) {
+                            Ok(file_object) => Some(file_object),
+                            Err(e) => return e.into_response(),
                         }
-                        false => {
-                            let err_msg =
-                                "Failed to get the negative prompt. The negative prompt field in the request should be a text field.";
+                    }
+                    _ => None,
+                };
+                if control_image.is_some() {
+                    if let Some(map) = value.as_object_mut() {
+                        map.remove("control_image");
+                    }
+                }
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+                if let Err(e) = form::apply_json_defaults(&mut value, false) {
+                    return e.into_response();
+                }
+                sample_method = value
+                    .get("sample_method")
+                    .and_then(|sample_method| sample_method.as_str())
+                    .map(|sample_method| sample_method.to_string());
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "model" => match field.is_text() {
-                        true => {
-                            let mut model = String::new();
+                let mut image_request: ImageCreateRequest = match serde_json::from_value(value) {
+                    Ok(image_request) => image_request,
+                    Err(e) => {
+                        let err_msg =
+                            format!("Fail to deserialize image create request: {msg}", msg = e);
 
-                            if let Err(e) = field.data.read_to_string(&mut model) {
-                                let err_msg = format!("Failed to read the model. {}", e);
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                        return error::bad_request(err_msg);
+                    }
+                };
+                if control_image.is_some() {
+                    image_request.control_image = control_image;
+                }
 
-                                return error::internal_server_error(err_msg);
-                            }
+                image_request
+            } else {
+                return error::method_not_allowed(req.method());
+            }
+        }
+    };
 
-                            image_request.model = model;
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the model name. The model field in the request should be a text field.";
+    if let Err(e) = form::apply_loras(&mut image_request.prompt, &lora_refs) {
+        return e.into_response();
+    }
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+    if let Err(e) = validate::validate_create(&image_request, sample_method.as_deref()) {
+        return e.into_response();
+    }
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "n" => match field.is_text() {
-                        true => {
-                            let mut n = String::new();
+    if !is_served_model(&image_request.model) {
+        return model_not_found(&image_request.model);
+    }
 
-                            if let Err(e) = field.data.read_to_string(&mut n) {
-                                let err_msg = format!("Failed to read the number of images. {}", e);
+    let id = image_request.user.get_or_insert_with(gen_image_id).clone();
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+    // log user id
+    info!(target: "stdout", "user: {}", &id);
 
-                                return error::internal_server_error(err_msg);
-                            }
+    if async_mode && stream_mode {
+        let err_msg = "The `async` and `stream` options cannot be used together.";
 
-                            match n.parse::<u64>() {
-                                Ok(n) => image_request.n = Some(n),
-                                Err(e) => {
-                                    let err_msg = format!(
-                                        "Failed to parse the number of images. Reason: {}",
-                                        e
-                                    );
+        // log
+        error!(target: "stdout", "{}", &err_msg);
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+        return error::bad_request(err_msg);
+    }
 
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                            "Failed to get the number of images. The n field in the request should be a text field.";
+    // count the request against the rate limits of the key
+    let cost = limits::image_cost(
+        image_request.n,
+        image_request.steps,
+        image_request.height,
+        image_request.width,
+    );
+    let charge = match limits::acquire(key_info.as_ref(), cost) {
+        Ok(charge) => charge,
+        Err(e) => return e.into_response(),
+    };
+    let rate_limit = charge.status().clone();
+    let key_name = key_info.map(|key_info| key_info.name);
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+    // take a place in the generation queue
+    let ticket = match queue::enqueue() {
+        Ok(ticket) => ticket,
+        Err(e) => return queue_unavailable(e),
+    };
+    uploads.keep();
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "size" => {
-                        match field.is_text() {
-                            true => {
-                                let mut size = String::new();
+    if stream_mode {
+        // log
+        info!(target: "stdout", "Send the image generation event stream.");
 
-                                if let Err(e) = field.data.read_to_string(&mut size) {
-                                    let err_msg = format!("Failed to read the size. {}", e);
+        let mut res = stream_response(ticket, id, async move {
+            create_images(&mut image_request, key_name, charge).await
+        });
+        rate_limit.apply(&mut res);
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+        return res;
+    }
 
-                                    return error::internal_server_error(err_msg);
-                                }
+    if async_mode {
+        let job = jobs::create_job(ticket.id(), key_name.clone());
 
-                                // image_request.size = Some(size);
+        // log
+        info!(target: "stdout", "job_id: {}", &job.id);
 
-                                let parts: Vec<&str> = size.split('x').collect();
-                                if parts.len() != 2 {
-                                    let err_msg = "Invalid size format. The correct format is `HeightxWidth`. Example: 256x256";
+        let mut res = job_response(&job.id, None, hyper::StatusCode::ACCEPTED);
+        rate_limit.apply(&mut res);
 
-                                    // log
-                                    error!(target: "stdout", "{}", err_msg);
+        // run the image generation in the background
+        tokio::spawn(async move {
+            // let the response reach the client before the generation occupies the runtime
+            tokio::task::yield_now().await;
 
-                                    return error::bad_request(err_msg);
-                                }
-                                image_request.height = Some(parts[0].parse().unwrap());
-                                image_request.width = Some(parts[1].parse().unwrap());
-                            }
-                            false => {
-                                let err_msg =
-                                "Failed to get the size. The size field in the request should be a text field.";
+            tokio::select! {
+                _ = ticket.wait_turn() => {}
+                _ = job.cancelled.notified() => {
+                    // log
+                    info!(target: "stdout", "Image generation job {} is cancelled while queued.", &job.id);
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                    return;
+                }
+            }
 
-                                return error::internal_server_error(err_msg);
-                            }
-                        }
-                    }
-                    "response_format" => match field.is_text() {
-                        true => {
-                            let mut response_format = String::new();
+            if !jobs::mark_running(&job.id) {
+                return;
+            }
 
-                            if let Err(e) = field.data.read_to_string(&mut response_format) {
-                                let err_msg = format!("Failed to read the response format. {}", e);
+            match create_images(&mut image_request, key_name, charge).await {
+                Ok(images_response) => jobs::mark_succeeded(&job.id, images_response),
+                Err(err_msg) => jobs::mark_failed(&job.id, err_msg),
+            }
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+            // log
+            info!(target: "stdout", "Image generation job {} finished.", &job.id);
+        });
 
-                                return error::internal_server_error(err_msg);
-                            }
+        // log
+        info!(target: "stdout", "Send the image generation job response.");
 
-                            match response_format.parse::<ResponseFormat>() {
-                                Ok(format) => image_request.response_format = Some(format),
-                                Err(e) => {
-                                    let err_msg = format!(
-                                        "Failed to parse the response format. Reason: {}",
-                                        e
-                                    );
+        return res;
+    }
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+    ticket.wait_turn().await;
 
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the response format. The response format field in the request should be a text field.";
+    let mut res = match create_images(&mut image_request, key_name, charge).await {
+        Ok(images_response) => {
+            // serialize embedding object
+            match serde_json::to_string(&images_response) {
+                Ok(s) => {
+                    // return response
+                    let result = Response::builder()
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Methods", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .header("Content-Type", "application/json")
+                        .header("user", id)
+                        .body(Body::from(s));
+                    match result {
+                        Ok(response) => response,
+                        Err(e) => {
+                            let err_msg = e.to_string();
 
                             // log
                             error!(target: "stdout", "{}", &err_msg);
 
-                            return error::internal_server_error(err_msg);
+                            error::internal_server_error(err_msg)
                         }
-                    },
-                    "user" => match field.is_text() {
-                        true => {
-                            let mut user = String::new();
+                    }
+                }
+                Err(e) => {
+                    let err_msg =
+                        format!("Fail to serialize the `ListImagesResponse` instance. {}", e);
 
-                            if let Err(e) = field.data.read_to_string(&mut user) {
-                                let err_msg = format!("Failed to read the user. {}", e);
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        Err(err_msg) => error::backend_error(err_msg),
+    };
+    rate_limit.apply(&mut res);
 
-                                return error::internal_server_error(err_msg);
-                            }
+    // log
+    info!(target: "stdout", "Send the image generation response.");
 
-                            image_request.user = Some(user);
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the user. The user field in the request should be a text field.";
+    res
+}
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+/// Generate images for the request and turn the image urls into download urls.
+/// The usage of the completed generation is recorded for the key of the request, and the charge
+/// of the request is kept; a failed generation drops it, which refunds the request.
+async fn create_images(
+    image_request: &mut ImageCreateRequest,
+    key_name: Option<String>,
+    charge: limits::Charge,
+) -> Result<ListImagesResponse, String> {
+    let started = Instant::now();
+    let mut images_response = match llama_core::images::image_generation(image_request).await {
+        Ok(images_response) => images_response,
+        Err(e) => {
+            let err_msg = format!("Failed to get image generations. Reason: {}", e);
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "cfg_scale" => match field.is_text() {
-                        true => {
-                            let mut cfg_scale = String::new();
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                            if let Err(e) = field.data.read_to_string(&mut cfg_scale) {
-                                let err_msg = format!("Failed to read the cfg_config. {}", e);
+            return Err(err_msg);
+        }
+    };
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+    charge.keep();
+    record_generation(UsageRecord {
+        key: key_name,
+        endpoint: "images.generations".to_string(),
+        model: models::model_name().unwrap_or_default(),
+        images: images_response.data.len() as u64,
+        height: image_request
+            .height
+            .map(|height| height as u64)
+            .unwrap_or(limits::DEFAULT_SIZE),
+        width: image_request
+            .width
+            .map(|width| width as u64)
+            .unwrap_or(limits::DEFAULT_SIZE),
+        steps: Some(
+            image_request
+                .steps
+                .map(|steps| steps as u64)
+                .unwrap_or(limits::DEFAULT_STEPS),
+        ),
+        sample_method: sample_method_name(image_request.sample_method.as_ref()),
+        ..UsageRecord::finished(started)
+    });
+
+    if Some(ResponseFormat::Url) == image_request.response_format {
+        for image_object in images_response.data.iter_mut() {
+            let segments: Vec<&str> = image_object
+                .url
+                .as_deref()
+                .unwrap_or_default()
+                .split("/")
+                .collect();
+            match segments.as_slice() {
+                [_, _, id, filename] => {
+                    let url = download_url(&format!("{}/{}", id, filename))?;
+
+                    info!(target: "stdout", "url: {}", url);
+
+                    image_object.url = Some(url);
+                }
+                _ => {
+                    let err_msg = "Failed to parse the url from the image response.";
 
-                                return error::internal_server_error(err_msg);
-                            }
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                            match cfg_scale.parse::<f32>() {
-                                Ok(scale) => image_request.cfg_scale = Some(scale),
-                                Err(e) => {
-                                    let err_msg = format!(
-                                        "Failed to parse the number of images. Reason: {}",
-                                        e
-                                    );
+                    return Err(err_msg.to_string());
+                }
+            }
+        }
+    }
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+    Ok(images_response)
+}
 
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the cfg_config. The cfg_config field in the request should be a text field.";
+/// Record a completed image request in the usage file and the metrics.
+fn record_generation(record: UsageRecord) {
+    metrics::observe_generation(&record);
+    usage::record(record);
+}
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+/// The name of the sampling method, as sent by the clients, e.g. `euler_a`.
+fn sample_method_name(sample_method: Option<&SamplingMethod>) -> Option<String> {
+    sample_method
+        .and_then(|sample_method| serde_json::to_value(sample_method).ok())
+        .and_then(|value| value.as_str().map(|name| name.to_string()))
+}
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "sample_method" => match field.is_text() {
-                        true => {
-                            let mut sample_method = String::new();
+/// Build the download url of the file at the given path below `/v1/files/download/`.
+fn download_url(path: &str) -> Result<String, String> {
+    // get the socket address of request
+    let download_url_prefix = match DOWNLOAD_URL_PREFIX.get() {
+        Some(download_url_prefix) => download_url_prefix,
+        None => {
+            let err_msg = "The download url prefix is not set.";
 
-                            if let Err(e) = field.data.read_to_string(&mut sample_method) {
-                                let err_msg = format!("Failed to read the sample_method. {}", e);
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+            return Err(err_msg.to_string());
+        }
+    };
 
-                                return error::internal_server_error(err_msg);
-                            }
+    let host = match (download_url_prefix.host_str(), download_url_prefix.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => {
+            let err_msg = format!(
+                "The download url prefix {} has no host.",
+                download_url_prefix
+            );
 
-                            image_request.sample_method = Some(sample_method.as_str().into());
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the sample_method. The sample_method field in the request should be a text field.";
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+            return Err(err_msg);
+        }
+    };
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "steps" => match field.is_text() {
-                        true => {
-                            let mut steps = String::new();
+    Ok(format!(
+        "{}://{}/v1/files/download/{}",
+        download_url_prefix.scheme(),
+        host,
+        path
+    ))
+}
 
-                            if let Err(e) = field.data.read_to_string(&mut steps) {
-                                let err_msg = format!("Failed to read the steps. {}", e);
+pub(crate) async fn image_edit_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming image generation request");
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+    if req.method().eq(&hyper::http::Method::OPTIONS) {
+        let result = Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Access-Control-Allow-Methods", "*")
+            .header("Access-Control-Allow-Headers", "*")
+            .header("Content-Type", "application/json")
+            .body(Body::empty());
 
-                                return error::internal_server_error(err_msg);
-                            }
+        match result {
+            Ok(response) => return response,
+            Err(e) => {
+                let err_msg = e.to_string();
 
-                            match steps.parse::<usize>() {
-                                Ok(steps) => image_request.steps = Some(steps),
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse the steps. Reason: {}", e);
+                // log
+                error!(target: "stdout", "{}", &err_msg);
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
-
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the steps. The steps field in the request should be a text field.";
-
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+                return error::internal_server_error(err_msg);
+            }
+        }
+    }
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "height" => match field.is_text() {
-                        true => {
-                            let mut height = String::new();
+    let res = match *req.method() {
+        Method::POST => {
+            // the key of the request, whose rate limits apply
+            let key_info = req.extensions().get::<KeyInfo>().cloned();
 
-                            if let Err(e) = field.data.read_to_string(&mut height) {
-                                let err_msg = format!("Failed to read the height. {}", e);
+            // check if the client asks for the progress events
+            let mut stream_mode = query_flag(&req, "stream");
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+            let mut form = match form::read_form(req).await {
+                Ok(form) => form,
+                Err(e) => return e.into_response(),
+            };
 
-                                return error::internal_server_error(err_msg);
-                            }
+            // the `stream` field is handled by the server, not by the backend
+            match form.take_bool("stream") {
+                Ok(Some(stream)) => stream_mode = stream,
+                Ok(None) => {}
+                Err(e) => return e.into_response(),
+            }
 
-                            match height.parse::<usize>() {
-                                Ok(height) => image_request.height = Some(height),
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse the height. Reason: {}", e);
+            let lora_refs = match form.take_loras() {
+                Ok(loras) => loras.unwrap_or_default(),
+                Err(e) => return e.into_response(),
+            };
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+            if let Err(e) = form.apply_defaults(true) {
+                return e.into_response();
+            }
+            let sample_method = form.text("sample_method");
 
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the height. The height field in the request should be a text field.";
+            let (mut image_request, uploads) = match form.extract::<ImageEditRequest>() {
+                Ok(extracted) => extracted,
+                Err(e) => return e.into_response(),
+            };
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+            // log
+            info!(target: "stdout", "image edit request: {:?}", &image_request);
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "width" => match field.is_text() {
-                        true => {
-                            let mut width = String::new();
+            if let Err(e) = form::apply_loras(&mut image_request.prompt, &lora_refs) {
+                return e.into_response();
+            }
 
-                            if let Err(e) = field.data.read_to_string(&mut width) {
-                                let err_msg = format!("Failed to read the width. {}", e);
+            if let Err(e) = validate::validate_edit(&image_request, sample_method.as_deref()) {
+                return e.into_response();
+            }
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+            if !is_served_model(&image_request.model) {
+                return model_not_found(&image_request.model);
+            }
 
-                                return error::internal_server_error(err_msg);
-                            }
+            // check if the user id is provided
+            let id = image_request.user.get_or_insert_with(gen_image_id).clone();
 
-                            match width.parse::<usize>() {
-                                Ok(width) => image_request.width = Some(width),
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse the width. Reason: {}", e);
+            // log user id
+            info!(target: "stdout", "user: {}", &id);
+
+            // count the request against the rate limits of the key
+            let cost = limits::image_cost(
+                image_request.n,
+                image_request.steps,
+                image_request.height,
+                image_request.width,
+            );
+            let charge = match limits::acquire(key_info.as_ref(), cost) {
+                Ok(charge) => charge,
+                Err(e) => return e.into_response(),
+            };
+            let rate_limit = charge.status().clone();
+            let key_name = key_info.map(|key_info| key_info.name);
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+            // take a place in the generation queue
+            let ticket = match queue::enqueue() {
+                Ok(ticket) => ticket,
+                Err(e) => return queue_unavailable(e),
+            };
+            uploads.keep();
 
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the width. The width field in the request should be a text field.";
+            if stream_mode {
+                // log
+                info!(target: "stdout", "Send the image edit event stream.");
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+                let mut res = stream_response(ticket, id, async move {
+                    edit_images(&mut image_request, key_name, charge).await
+                });
+                rate_limit.apply(&mut res);
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "control_strength" => match field.is_text() {
-                        true => {
-                            let mut control_strength = String::new();
+                return res;
+            }
 
-                            if let Err(e) = field.data.read_to_string(&mut control_strength) {
-                                let err_msg = format!("Failed to read the control_strength. {}", e);
+            ticket.wait_turn().await;
+
+            let mut res = match edit_images(&mut image_request, key_name, charge).await {
+                Ok(images_response) => match serde_json::to_string(&images_response) {
+                    Ok(s) => {
+                        // return response
+                        let result = Response::builder()
+                            .header("Access-Control-Allow-Origin", "*")
+                            .header("Access-Control-Allow-Methods", "*")
+                            .header("Access-Control-Allow-Headers", "*")
+                            .header("Content-Type", "application/json")
+                            .header("user", id)
+                            .body(Body::from(s));
+                        match result {
+                            Ok(response) => response,
+                            Err(e) => {
+                                let err_msg = e.to_string();
 
                                 // log
                                 error!(target: "stdout", "{}", &err_msg);
 
-                                return error::internal_server_error(err_msg);
+                                error::internal_server_error(err_msg)
                             }
+                        }
+                    }
+                    Err(e) => {
+                        let err_msg =
+                            format!("Fail to serialize the `ListImagesResponse` instance. {}", e);
 
-                            match control_strength.parse::<f32>() {
-                                Ok(control_strength) => {
-                                    image_request.control_strength = Some(control_strength)
-                                }
-                                Err(e) => {
-                                    let err_msg = format!(
-                                        "Failed to parse the control_strength. Reason: {}",
-                                        e
-                                    );
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+                        error::internal_server_error(err_msg)
+                    }
+                },
+                Err(err_msg) => error::backend_error(err_msg),
+            };
+            rate_limit.apply(&mut res);
 
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the control_strength. The control_strength field in the request should be a text field.";
+            res
+        }
+        _ => error::method_not_allowed(req.method()),
+    };
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+    // log
+    info!(target: "stdout", "Send the image edit response.");
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "control_image" => {
-                        let filename = match field.headers.filename {
-                            Some(filename) => filename,
-                            None => {
-                                let err_msg =
-                                    "Failed to upload the image file. The filename is not provided.";
+    res
+}
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+/// Edit images for the request and turn the image urls into download urls.
+///
+/// The usage of the completed edit is recorded for the key of the request, and the charge of the
+/// request is kept; a failed edit drops it, which refunds the request.
+async fn edit_images(
+    image_request: &mut ImageEditRequest,
+    key_name: Option<String>,
+    charge: limits::Charge,
+) -> Result<ListImagesResponse, String> {
+    let started = Instant::now();
+    let mut images_response = match llama_core::images::image_edit(image_request).await {
+        Ok(images_response) => images_response,
+        Err(e) => {
+            let err_msg = format!("Failed to get image edit result. Reason: {}", e);
 
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                        // get the image data
-                        let mut buffer = Vec::new();
-                        let size_in_bytes = match field.data.read_to_end(&mut buffer) {
-                            Ok(size_in_bytes) => size_in_bytes,
-                            Err(e) => {
-                                let err_msg = format!("Failed to read the image file. {}", e);
+            return Err(err_msg);
+        }
+    };
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+    charge.keep();
+    record_generation(UsageRecord {
+        key: key_name,
+        endpoint: "images.edits".to_string(),
+        model: models::model_name().unwrap_or_default(),
+        images: images_response.data.len() as u64,
+        height: image_request
+            .height
+            .map(|height| height as u64)
+            .unwrap_or(limits::DEFAULT_SIZE),
+        width: image_request
+            .width
+            .map(|width| width as u64)
+            .unwrap_or(limits::DEFAULT_SIZE),
+        steps: Some(
+            image_request
+                .steps
+                .map(|steps| steps as u64)
+                .unwrap_or(limits::DEFAULT_STEPS),
+        ),
+        sample_method: sample_method_name(image_request.sample_method.as_ref()),
+        ..UsageRecord::finished(started)
+    });
+
+    if Some(ResponseFormat::Url) == image_request.response_format {
+        for image_object in images_response.data.iter_mut() {
+            let segments: Vec<&str> = image_object
+                .url
+                .as_deref()
+                .unwrap_or_default()
+                .split("/")
+                .collect();
+            match segments.as_slice() {
+                [_, _, id, filename] => {
+                    let url = download_url(&format!("{}/{}", id, filename))?;
+
+                    info!(target: "stdout", "url: {}", url);
+
+                    image_object.url = Some(url);
+                }
+                _ => {
+                    let err_msg = "Failed to parse the url from the image response.";
 
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                        // create a file id for the image file
-                        let id = format!("file_{}", uuid::Uuid::new_v4());
+                    return Err(err_msg.to_string());
+                }
+            }
+        }
+    }
 
-                        // save the file
-                        let path = Path::new("archives");
-                        if !path.exists() {
-                            fs::create_dir(path).unwrap();
-                        }
-                        let file_path = path.join(&id);
-                        if !file_path.exists() {
-                            fs::create_dir(&file_path).unwrap();
-                        }
-                        let mut file = match File::create(file_path.join(&filename)) {
-                            Ok(file) => file,
-                            Err(e) => {
-                                let err_msg = format!(
-                                    "Failed to create archive document {}. {}",
-                                    &filename, e
-                                );
+    Ok(images_response)
+}
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+pub(crate) async fn image_variation_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming image variation request");
 
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
-                        file.write_all(&buffer[..]).unwrap();
+    if req.method().eq(&hyper::http::Method::OPTIONS) {
+        let result = Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Access-Control-Allow-Methods", "*")
+            .header("Access-Control-Allow-Headers", "*")
+            .header("Content-Type", "application/json")
+            .body(Body::empty());
 
-                        // log
-                        info!(target: "stdout", "file_id: {}, file_name: {}, size in bytes: {}", &id, &filename, size_in_bytes);
+        match result {
+            Ok(response) => return response,
+            Err(e) => {
+                let err_msg = e.to_string();
+
+                // log
+                error!(target: "stdout", "{}", &err_msg);
 
-                        let created_at =
-                            match SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
-                                Ok(n) => n.as_secs(),
-                                Err(_) => {
-                                    let err_msg = "Failed to get the current time.";
+                return error::internal_server_error(err_msg);
+            }
+        }
+    }
 
-                                    // log
-                                    error!(target: "stdout", "{}", err_msg);
+    let res = match *req.method() {
+        Method::POST => {
+            // the key of the request, whose rate limits apply
+            let key_info = req.extensions().get::<KeyInfo>().cloned();
+
+            let (mut image_request, uploads) = match form::read_form(req).await {
+                Ok(form) => match form.extract::<ImageVariationRequest>() {
+                    Ok(extracted) => extracted,
+                    Err(e) => return e.into_response(),
+                },
+                Err(e) => return e.into_response(),
+            };
 
-                                    return error::internal_server_error(err_msg);
-                                }
-                            };
+            // log
+            info!(target: "stdout", "image variation request: {:?}", &image_request);
 
-                        // create a file object
-                        image_request.control_image = Some(FileObject {
-                            id,
-                            bytes: size_in_bytes as u64,
-                            created_at,
-                            filename,
-                            object: "file".to_string(),
-                            purpose: "assistants".to_string(),
-                        });
-                    }
-                    "seed" => match field.is_text() {
-                        true => {
-                            let mut seed = String::new();
+            if let Err(e) = validate::validate_variation(&image_request) {
+                return e.into_response();
+            }
 
-                            if let Err(e) = field.data.read_to_string(&mut seed) {
-                                let err_msg = format!("Failed to read the seed. {}", e);
+            if !is_served_model(&image_request.model) {
+                return model_not_found(&image_request.model);
+            }
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+            // check if the user id is provided
+            let id = image_request.user.get_or_insert_with(gen_image_id).clone();
 
-                                return error::internal_server_error(err_msg);
-                            }
+            // log user id
+            info!(target: "stdout", "user: {}", &id);
+
+            // count the request against the rate limits of the key
+            let (height, width) = match &image_request.size {
+                Some(size) => {
+                    match form::parse_size("size", form::FieldValue::Text(size.clone())) {
+                        Ok((height, width)) => (Some(height), Some(width)),
+                        Err(e) => return e.into_response(),
+                    }
+                }
+                None => (None, None),
+            };
+            let cost = limits::image_cost(image_request.n, None, height, width);
+            let charge = match limits::acquire(key_info.as_ref(), cost) {
+                Ok(charge) => charge,
+                Err(e) => return e.into_response(),
+            };
+            let rate_limit = charge.status().clone();
+
+            // wait for the turn in the generation queue
+            let ticket = match queue::enqueue() {
+                Ok(ticket) => ticket,
+                Err(e) => return queue_unavailable(e),
+            };
+            uploads.keep();
+            ticket.wait_turn().await;
 
-                            match seed.parse::<i32>() {
-                                Ok(seed) => image_request.seed = Some(seed),
+            let started = Instant::now();
+            let mut res = match llama_core::images::image_variation(&mut image_request).await {
+                Ok(images_response) => {
+                    charge.keep();
+                    record_generation(UsageRecord {
+                        key: key_info.map(|key_info| key_info.name),
+                        endpoint: "images.variations".to_string(),
+                        model: models::model_name().unwrap_or_default(),
+                        images: images_response.data.len() as u64,
+                        height: height
+                            .map(|height| height as u64)
+                            .unwrap_or(limits::DEFAULT_SIZE),
+                        width: width
+                            .map(|width| width as u64)
+                            .unwrap_or(limits::DEFAULT_SIZE),
+                        ..UsageRecord::finished(started)
+                    });
+
+                    match serde_json::to_string(&images_response) {
+                        Ok(s) => {
+                            // return response
+                            let result = Response::builder()
+                                .header("Access-Control-Allow-Origin", "*")
+                                .header("Access-Control-Allow-Methods", "*")
+                                .header("Access-Control-Allow-Headers", "*")
+                                .header("Content-Type", "application/json")
+                                .header("user", id)
+                                .body(Body::from(s));
+                            match result {
+                                Ok(response) => response,
                                 Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse the seed. Reason: {}", e);
+                                    let err_msg = e.to_string();
 
                                     // log
                                     error!(target: "stdout", "{}", &err_msg);
 
-                                    return error::bad_request(err_msg);
+                                    error::internal_server_error(err_msg)
                                 }
                             }
                         }
-                        false => {
-                            let err_msg =
-                                "Failed to get the seed. The seed field in the request should be a text field.";
+                        Err(e) => {
+                            let err_msg = format!(
+                                "Fail to serialize the `ListImagesResponse` instance. {}",
+                                e
+                            );
 
                             // log
                             error!(target: "stdout", "{}", &err_msg);
 
-                            return error::internal_server_error(err_msg);
+                            error::internal_server_error(err_msg)
                         }
-                    },
-                    unsupported_field => {
-                        let err_msg = format!("Unsupported field: {}", unsupported_field);
-
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
-
-                        return error::bad_request(err_msg);
                     }
                 }
-            }
-
-            image_request
-        }
-        _ => {
-            if req.method() == Method::POST {
-                info!(target: "stdout", "Prepare the image generation request.");
-
-                // parse request
-                let body_bytes = match to_bytes(req.body_mut()).await {
-                    Ok(body_bytes) => body_bytes,
-                    Err(e) => {
-                        let err_msg = format!("Fail to read buffer from request body. {}", e);
-
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
-
-                        return error::internal_server_error(err_msg);
-                    }
-                };
-                let image_request: ImageCreateRequest = match serde_json::from_slice(&body_bytes) {
-                    Ok(image_request) => image_request,
-                    Err(e) => {
-                        let err_msg =
-                            format!("Fail to deserialize image create request: {msg}", msg = e);
-
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
-
-                        return error::bad_request(err_msg);
-                    }
-                };
+                Err(e) => {
+                    let err_msg = format!("Failed to get image variation result. Reason: {}", e);
 
-                image_request
-            } else {
-                let err_msg = "Invalid HTTP Method.";
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                // log
-                error!(target: "stdout", "{}", &err_msg);
+                    error::backend_error(err_msg)
+                }
+            };
+            rate_limit.apply(&mut res);
 
-                return error::internal_server_error(err_msg);
-            }
+            res
         }
+        _ => error::method_not_allowed(req.method()),
     };
 
-    if image_request.user.is_none() {
-        image_request.user = Some(gen_image_id())
-    };
-    let id = image_request.user.clone().unwrap();
-
-    // log user id
-    info!(target: "stdout", "user: {}", image_request.user.clone().unwrap());
-
-    let res = match llama_core::images::image_generation(&mut image_request).await {
-        Ok(mut images_response) => {
-            if Some(ResponseFormat::Url) == image_request.response_format {
-                for image_object in images_response.data.iter_mut() {
-                    let segments: Vec<&str> =
-                        image_object.url.as_ref().unwrap().split("/").collect();
-                    match segments.as_slice() {
-                        [_, _, id, filename] => {
-                            // get the socket address of request
-                            let download_url_prefix = DOWNLOAD_URL_PREFIX.get().unwrap();
-
-                            let host = match download_url_prefix.port() {
-                                Some(port) => {
-                                    format!("{}:{}", download_url_prefix.host_str().unwrap(), port)
-                                }
-                                None => download_url_prefix.host_str().unwrap().to_string(),
-                            };
+    // log
+    info!(target: "stdout", "Send the image variation response.");
 
-                            let url = format!(
-                                "{}://{}/v1/files/download/{}/{}",
-                                download_url_prefix.scheme(),
-                                host,
-                                id,
-                                filename
-                            );
+    res
+}
 
-                            info!(target: "stdout", "url: {}", url);
+/// Retrieve and cancel the image generation jobs. A key without the `admin` scope only sees the
+/// jobs it created; the jobs of other keys are not found.
+///
+/// - `GET /v1/jobs`: List all jobs.
+/// - `GET /v1/jobs/{job_id}`: Retrieve a job by id.
+/// - `DELETE /v1/jobs/{job_id}`: Cancel a job by id.
+/// - `POST /v1/jobs/{job_id}/cancel`: Cancel a job by id.
+///
+pub(crate) async fn jobs_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming jobs request");
 
-                            image_object.url = Some(url);
-                        }
-                        _ => {
-                            let err_msg = "Failed to parse the url from the image response.";
+    // a key without the `admin` scope only sees its own jobs
+    let owner = req
+        .extensions()
+        .get::<KeyInfo>()
+        .filter(|key_info| !key_info.allows(keys::Scope::Admin))
+        .map(|key_info| key_info.name.clone());
+    let owner = owner.as_deref();
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+    let res = if req.method() == Method::GET {
+        let uri_path = req.uri().path().trim_end_matches('/');
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    }
-                }
-            }
+        // Split the path into segments
+        let segments: Vec<&str> = uri_path.split('/').collect();
 
-            // serialize embedding object
-            match serde_json::to_string(&images_response) {
+        match segments.as_slice() {
+            ["", "v1", "jobs"] => match jobs::list_jobs_to_json(owner) {
                 Ok(s) => {
                     // return response
                     let result = Response::builder()
@@ -746,8 +928,8 @@ pub(crate) async fn image_generation_handler(mut req: Request<Body>) -> Response
                         .header("Access-Control-Allow-Methods", "*")
                         .header("Access-Control-Allow-Headers", "*")
                         .header("Content-Type", "application/json")
-                        .header("user", id)
                         .body(Body::from(s));
+
                     match result {
                         Ok(response) => response,
                         Err(e) => {
@@ -761,37 +943,43 @@ pub(crate) async fn image_generation_handler(mut req: Request<Body>) -> Response
                     }
                 }
                 Err(e) => {
-                    let err_msg =
-                        format!("Fail to serialize the `ListImagesResponse` instance. {}", e);
+                    let err_msg = format!("Failed to serialize the job list. {}", e);
 
                     // log
                     error!(target: "stdout", "{}", &err_msg);
 
                     error::internal_server_error(err_msg)
                 }
-            }
-        }
-        Err(e) => {
-            let err_msg = format!("Failed to get image generations. Reason: {}", e);
+            },
+            ["", "v1", "jobs", job_id] => job_response(job_id, owner, hyper::StatusCode::OK),
+            _ => {
+                let err_msg = format!("unsupported uri path: {}", uri_path);
 
-            // log
-            error!(target: "stdout", "{}", &err_msg);
+                // log
+                error!(target: "stdout", "{}", &err_msg);
 
-            error::internal_server_error(err_msg)
+                error::invalid_endpoint(err_msg)
+            }
         }
-    };
+    } else if req.method() == Method::DELETE || req.method() == Method::POST {
+        let uri_path = req.uri().path().trim_end_matches('/');
 
-    // log
-    info!(target: "stdout", "Send the image generation response.");
+        // Split the path into segments
+        let segments: Vec<&str> = uri_path.split('/').collect();
 
-    res
-}
+        match (req.method(), segments.as_slice()) {
+            (&Method::DELETE, ["", "v1", "jobs", job_id])
+            | (&Method::POST, ["", "v1", "jobs", job_id, "cancel"]) => cancel_job(job_id, owner),
+            _ => {
+                let err_msg = format!("unsupported uri path: {}", uri_path);
 
-pub(crate) async fn image_edit_handler(req: Request<Body>) -> Response<Body> {
-    // log
-    info!(target: "stdout", "Handling the coming image generation request");
+                // log
+                error!(target: "stdout", "{}", &err_msg);
 
-    if req.method().eq(&hyper::http::Method::OPTIONS) {
+                error::invalid_endpoint(err_msg)
+            }
+        }
+    } else if req.method() == Method::OPTIONS {
         let result = Response::builder()
             .header("Access-Control-Allow-Origin", "*")
             .header("Access-Control-Allow-Methods", "*")
@@ -810,887 +998,398 @@ pub(crate) async fn image_edit_handler(req: Request<Body>) -> Response<Body> {
                 return error::internal_server_error(err_msg);
             }
         }
-    }
+    } else {
+        error::method_not_allowed(req.method())
+    };
 
-    let res = match *req.method() {
-        Method::POST => {
-            let boundary = "boundary=";
+    info!(target: "stdout", "Send the jobs response");
 
-            let boundary = req.headers().get("content-type").and_then(|ct| {
-                let ct = ct.to_str().ok()?;
-                let idx = ct.find(boundary)?;
-                Some(ct[idx + boundary.len()..].to_string())
-            });
+    res
+}
 
-            let req_body = req.into_body();
-            let body_bytes = match to_bytes(req_body).await {
-                Ok(body_bytes) => body_bytes,
-                Err(e) => {
-                    let err_msg = format!("Fail to read buffer from request body. {}", e);
+/// List the models served by the server, or retrieve one of them.
+///
+/// - `GET /v1/models`: List all models.
+/// - `GET /v1/models/{model_id}`: Retrieve a model by id.
+///
+pub(crate) async fn models_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming models request");
+
+    let res = match *req.method() {
+        Method::GET => {
+            let uri_path = req.uri().path().trim_end_matches('/');
+
+            let s = match uri_path.strip_prefix("/v1/models") {
+                Some("") => serde_json::to_string(&serde_json::json!({
+                    "object": "list",
+                    "data": models::list_models(),
+                })),
+                Some(model_id) => {
+                    let model_id = model_id.trim_start_matches('/');
+
+                    match models::retrieve_model(model_id) {
+                        Some(model) => serde_json::to_string(&model),
+                        None => return model_not_found(model_id),
+                    }
+                }
+                None => {
+                    let err_msg = format!("unsupported uri path: {}", uri_path);
 
                     // log
                     error!(target: "stdout", "{}", &err_msg);
 
-                    return error::internal_server_error(err_msg);
+                    return error::invalid_endpoint(err_msg);
                 }
             };
 
-            let cursor = Cursor::new(body_bytes.to_vec());
+            match s {
+                Ok(s) => {
+                    // return response
+                    let result = Response::builder()
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Methods", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .header("Content-Type", "application/json")
+                        .body(Body::from(s));
 
-            let mut multipart = Multipart::with_body(cursor, boundary.unwrap());
+                    match result {
+                        Ok(response) => response,
+                        Err(e) => {
+                            let err_msg = e.to_string();
 
-            let mut image_request = ImageEditRequest::default();
-            while let ReadEntryResult::Entry(mut field) = multipart.read_entry_mut() {
-                match &*field.headers.name {
-                    "image" => {
-                        let filename = match field.headers.filename {
-                            Some(filename) => filename,
-                            None => {
-                                let err_msg =
-                                    "Failed to upload the image file. The filename is not provided.";
+                            // log
+                            error!(target: "stdout", "{}", &err_msg);
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                            error::internal_server_error(err_msg)
+                        }
+                    }
+                }
+                Err(e) => {
+                    let err_msg = format!("Failed to serialize the models. {}", e);
 
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                        // get the image data
-                        let mut buffer = Vec::new();
-                        let size_in_bytes = match field.data.read_to_end(&mut buffer) {
-                            Ok(size_in_bytes) => size_in_bytes,
-                            Err(e) => {
-                                let err_msg = format!("Failed to read the image file. {}", e);
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        Method::OPTIONS => {
+            let result = Response::builder()
+                .header("Access-Control-Allow-Origin", "*")
+                .header("Access-Control-Allow-Methods", "*")
+                .header("Access-Control-Allow-Headers", "*")
+                .header("Content-Type", "application/json")
+                .body(Body::empty());
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+            match result {
+                Ok(response) => response,
+                Err(e) => {
+                    let err_msg = e.to_string();
 
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                        // create a file id for the image file
-                        let id = format!("file_{}", uuid::Uuid::new_v4());
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        _ => error::method_not_allowed(req.method()),
+    };
 
-                        // save the file
-                        let path = Path::new("archives");
-                        if !path.exists() {
-                            fs::create_dir(path).unwrap();
-                        }
-                        let file_path = path.join(&id);
-                        if !file_path.exists() {
-                            fs::create_dir(&file_path).unwrap();
-                        }
-                        let mut file = match File::create(file_path.join(&filename)) {
-                            Ok(file) => file,
-                            Err(e) => {
-                                let err_msg = format!(
-                                    "Failed to create archive document {}. {}",
-                                    &filename, e
-                                );
+    info!(target: "stdout", "Send the models response");
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+    res
+}
 
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
-                        file.write_all(&buffer[..]).unwrap();
+/// Manage the named generation presets.
+///
+/// - `GET /v1/presets`: List the presets.
+/// - `GET /v1/presets/{name}`: Retrieve a preset.
+/// - `PUT /v1/presets/{name}`: Create or replace a preset with the parameters of the request body.
+/// - `DELETE /v1/presets/{name}`: Delete a preset.
+///
+pub(crate) async fn presets_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming presets request");
 
-                        // log
-                        info!(target: "stdout", "file_id: {}, file_name: {}, size in bytes: {}", &id, &filename, size_in_bytes);
+    let uri_path = req.uri().path().trim_end_matches('/').to_string();
+    let name = match uri_path.strip_prefix("/v1/presets") {
+        Some("") => None,
+        Some(name) if name.starts_with('/') => Some(name.trim_start_matches('/').to_string()),
+        _ => {
+            let err_msg = format!("unsupported uri path: {}", uri_path);
 
-                        let created_at =
-                            match SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
-                                Ok(n) => n.as_secs(),
-                                Err(_) => {
-                                    let err_msg = "Failed to get the current time.";
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                                    // log
-                                    error!(target: "stdout", "{}", err_msg);
+            return error::invalid_endpoint(err_msg);
+        }
+    };
 
-                                    return error::internal_server_error(err_msg);
-                                }
-                            };
+    let res = match (req.method().clone(), name) {
+        (Method::GET, None) => json_response(
+            serde_json::to_string(&serde_json::json!({
+                "object": "list",
+                "data": presets::list_presets(),
+            })),
+            hyper::StatusCode::OK,
+        ),
+        (Method::GET, Some(name)) => match presets::retrieve_preset(&name) {
+            Some(preset) => json_response(serde_json::to_string(&preset), hyper::StatusCode::OK),
+            None => preset_error(presets::PresetError::NotFound(name)),
+        },
+        (Method::PUT, Some(name)) => {
+            let body_bytes = match to_bytes(req.into_body()).await {
+                Ok(body_bytes) => body_bytes,
+                Err(e) => {
+                    let err_msg = format!("Fail to read buffer from request body. {}", e);
 
-                        // create a file object
-                        image_request.image = FileObject {
-                            id,
-                            bytes: size_in_bytes as u64,
-                            created_at,
-                            filename,
-                            object: "file".to_string(),
-                            purpose: "assistants".to_string(),
-                        };
-                    }
-                    "prompt" => match field.is_text() {
-                        true => {
-                            let mut prompt = String::new();
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                            if let Err(e) = field.data.read_to_string(&mut prompt) {
-                                let err_msg = format!("Failed to read the prompt. {}", e);
+                    return error::bad_request(err_msg);
+                }
+            };
+            let parameters: models::GenerationDefaults = match serde_json::from_slice(&body_bytes) {
+                Ok(parameters) => parameters,
+                Err(e) => {
+                    let err_msg = format!("Fail to deserialize the preset. {}", e);
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                                return error::internal_server_error(err_msg);
-                            }
+                    return error::bad_request(err_msg);
+                }
+            };
+            if let Err(e) = validate::validate_defaults(&parameters, models::is_diffusion_model()) {
+                return e.into_response();
+            }
 
-                            image_request.prompt = prompt;
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the prompt. The prompt field in the request should be a text field.";
+            match presets::save_preset(&name, parameters) {
+                Ok((preset, created)) => {
+                    let status = match created {
+                        true => hyper::StatusCode::CREATED,
+                        false => hyper::StatusCode::OK,
+                    };
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+                    json_response(serde_json::to_string(&preset), status)
+                }
+                Err(e) => preset_error(e),
+            }
+        }
+        (Method::DELETE, Some(name)) => match presets::delete_preset(&name) {
+            Ok(()) => json_response(
+                serde_json::to_string(&serde_json::json!({
+                    "id": name,
+                    "object": "preset",
+                    "deleted": true,
+                })),
+                hyper::StatusCode::OK,
+            ),
+            Err(e) => preset_error(e),
+        },
+        (Method::OPTIONS, _) => {
+            let result = Response::builder()
+                .header("Access-Control-Allow-Origin", "*")
+                .header("Access-Control-Allow-Methods", "*")
+                .header("Access-Control-Allow-Headers", "*")
+                .header("Content-Type", "application/json")
+                .body(Body::empty());
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "negative_prompt" => match field.is_text() {
-                        true => {
-                            let mut negative_prompt = String::new();
+            match result {
+                Ok(response) => response,
+                Err(e) => {
+                    let err_msg = e.to_string();
 
-                            if let Err(e) = field.data.read_to_string(&mut negative_prompt) {
-                                let err_msg = format!("Failed to read the prompt. {}", e);
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        (method, _) => error::method_not_allowed(method),
+    };
 
-                                return error::internal_server_error(err_msg);
-                            }
+    info!(target: "stdout", "Send the presets response");
 
-                            image_request.prompt = negative_prompt;
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the negative prompt. The negative prompt field in the request should be a text field.";
+    res
+}
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+/// Report the usage of the completed image requests.
+///
+/// - `GET /v1/usage`: Aggregate the usage by API key and model. The `from` and `to` query
+///   parameters bound the period, and the `key` query parameter keeps the usage of one API key.
+///
+pub(crate) async fn usage_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming usage request");
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "mask" => {
-                        let filename = match field.headers.filename {
-                            Some(filename) => filename,
-                            None => {
-                                let err_msg =
-                                    "Failed to upload the image mask file. The filename is not provided.";
+    let res = match *req.method() {
+        Method::GET => {
+            let mut from = None;
+            let mut to = None;
+            let mut key = None;
+            if let Some(query) = req.uri().query() {
+                for (name, value) in url::form_urlencoded::parse(query.as_bytes()) {
+                    match name.as_ref() {
+                        "from" | "to" => {
+                            let time = match usage::parse_time(&value, name == "to") {
+                                Some(time) => time,
+                                None => {
+                                    let err_msg = format!(
+                                        "Invalid `{}` value `{}`. The value should be a Unix timestamp in seconds or a `YYYY-MM-DD` date.",
+                                        name, value
+                                    );
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                                    // log
+                                    error!(target: "stdout", "{}", &err_msg);
 
-                                return error::internal_server_error(err_msg);
+                                    return ServerError::InvalidRequest {
+                                        message: err_msg,
+                                        param: Some(name.to_string()),
+                                        code: Some("invalid_value".to_string()),
+                                    }
+                                    .into_response();
+                                }
+                            };
+                            match name.as_ref() {
+                                "from" => from = Some(time),
+                                _ => to = Some(time),
                             }
-                        };
-
-                        // get the image data
-                        let mut buffer = Vec::new();
-                        let size_in_bytes = match field.data.read_to_end(&mut buffer) {
-                            Ok(size_in_bytes) => size_in_bytes,
-                            Err(e) => {
-                                let err_msg = format!("Failed to read the image mask file. {}", e);
+                        }
+                        "key" => key = Some(value.to_string()),
+                        _ => {}
+                    }
+                }
+            }
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+            // a key without the `admin` scope only sees its own usage
+            if let Some(key_info) = req.extensions().get::<KeyInfo>() {
+                if !key_info.allows(keys::Scope::Admin) {
+                    match &key {
+                        Some(key) if key != &key_info.name => {
+                            let err_msg = format!(
+                                "The API key `{}` can only read its own usage.",
+                                key_info.name
+                            );
+                            return error::forbidden(err_msg);
+                        }
+                        _ => key = Some(key_info.name.clone()),
+                    }
+                }
+            }
 
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
+            match usage::usage_report(from, to, key.as_deref()) {
+                Ok(report) => json_response(serde_json::to_string(&report), hyper::StatusCode::OK),
+                Err(e) => e.into_response(),
+            }
+        }
+        Method::OPTIONS => {
+            let result = Response::builder()
+                .header("Access-Control-Allow-Origin", "*")
+                .header("Access-Control-Allow-Methods", "*")
+                .header("Access-Control-Allow-Headers", "*")
+                .header("Content-Type", "application/json")
+                .body(Body::empty());
 
-                        // create a file id for the image file
-                        let id = format!("file_{}", uuid::Uuid::new_v4());
+            match result {
+                Ok(response) => response,
+                Err(e) => {
+                    let err_msg = e.to_string();
 
-                        // save the file
-                        let path = Path::new("archives");
-                        if !path.exists() {
-                            fs::create_dir(path).unwrap();
-                        }
-                        let file_path = path.join(&id);
-                        if !file_path.exists() {
-                            fs::create_dir(&file_path).unwrap();
-                        }
-                        let mut file = match File::create(file_path.join(&filename)) {
-                            Ok(file) => file,
-                            Err(e) => {
-                                let err_msg = format!(
-                                    "Failed to create archive document {}. {}",
-                                    &filename, e
-                                );
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        _ => error::method_not_allowed(req.method()),
+    };
 
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
-                        file.write_all(&buffer[..]).unwrap();
+    info!(target: "stdout", "Send the usage response");
 
-                        // log
-                        info!(target: "stdout", "file_id: {}, file_name: {}, size in bytes: {}", &id, &filename, size_in_bytes);
+    res
+}
 
-                        let created_at =
-                            match SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
-                                Ok(n) => n.as_secs(),
-                                Err(_) => {
-                                    let err_msg = "Failed to get the current time.";
+/// List the LoRA models found in the lora model directory.
+///
+/// - `GET /v1/loras`: List all LoRA models.
+///
+pub(crate) async fn loras_handler(req: Request<Body>) -> Response<Body> {
+    // log
+    info!(target: "stdout", "Handling the coming loras request");
 
-                                    // log
-                                    error!(target: "stdout", "{}", err_msg);
+    let res = match *req.method() {
+        Method::GET => {
+            let mut data = Vec::new();
+            if let Some(lora_model_dir) = models::lora_model_dir() {
+                match loras::list_loras(&lora_model_dir) {
+                    Ok(loras) => data.extend(loras),
+                    Err(e) => {
+                        let err_msg = format!(
+                            "Failed to read the lora model directory {}. {}",
+                            lora_model_dir, e
+                        );
 
-                                    return error::internal_server_error(err_msg);
-                                }
-                            };
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
 
-                        // create a file object
-                        image_request.mask = Some(FileObject {
-                            id,
-                            bytes: size_in_bytes as u64,
-                            created_at,
-                            filename,
-                            object: "file".to_string(),
-                            purpose: "assistants".to_string(),
-                        });
+                        return error::internal_server_error(err_msg);
                     }
-                    "model" => match field.is_text() {
-                        true => {
-                            let mut model = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut model) {
-                                let err_msg = format!("Failed to read the model. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                }
+            }
 
-                                return error::internal_server_error(err_msg);
-                            }
+            match serde_json::to_string(&serde_json::json!({
+                "object": "list",
+                "data": data,
+            })) {
+                Ok(s) => {
+                    // return response
+                    let result = Response::builder()
+                        .header("Access-Control-Allow-Origin", "*")
+                        .header("Access-Control-Allow-Methods", "*")
+                        .header("Access-Control-Allow-Headers", "*")
+                        .header("Content-Type", "application/json")
+                        .body(Body::from(s));
 
-                            image_request.model = model;
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the model name. The model field in the request should be a text field.";
+                    match result {
+                        Ok(response) => response,
+                        Err(e) => {
+                            let err_msg = e.to_string();
 
                             // log
                             error!(target: "stdout", "{}", &err_msg);
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "n" => match field.is_text() {
-                        true => {
-                            let mut n = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut n) {
-                                let err_msg = format!("Failed to read the number of images. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-
-                            match n.parse::<u64>() {
-                                Ok(n) => image_request.n = Some(n),
-                                Err(e) => {
-                                    let err_msg = format!(
-                                        "Failed to parse the number of images. Reason: {}",
-                                        e
-                                    );
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
-
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                            "Failed to get the number of images. The n field in the request should be a text field.";
-
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
-
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "size" => {
-                        match field.is_text() {
-                            true => {
-                                let mut size = String::new();
-
-                                if let Err(e) = field.data.read_to_string(&mut size) {
-                                    let err_msg = format!("Failed to read the size. {}", e);
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
-
-                                    return error::internal_server_error(err_msg);
-                                }
-
-                                // image_request.size = Some(size);
-
-                                let parts: Vec<&str> = size.split('x').collect();
-                                if parts.len() != 2 {
-                                    let err_msg = "Invalid size format. The correct format is `HeightxWidth`. Example: 256x256";
-
-                                    // log
-                                    error!(target: "stdout", "{}", err_msg);
-
-                                    return error::bad_request(err_msg);
-                                }
-                                image_request.height = Some(parts[0].parse().unwrap());
-                                image_request.width = Some(parts[1].parse().unwrap());
-                            }
-                            false => {
-                                let err_msg =
-                                "Failed to get the size. The size field in the request should be a text field.";
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
+                            error::internal_server_error(err_msg)
                         }
                     }
-                    "response_format" => match field.is_text() {
-                        true => {
-                            let mut response_format = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut response_format) {
-                                let err_msg = format!("Failed to read the response format. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-
-                            match response_format.parse::<ResponseFormat>() {
-                                Ok(format) => image_request.response_format = Some(format),
-                                Err(e) => {
-                                    let err_msg = format!(
-                                        "Failed to parse the response format. Reason: {}",
-                                        e
-                                    );
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
-
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the response format. The response format field in the request should be a text field.";
-
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
-
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "user" => match field.is_text() {
-                        true => {
-                            let mut user = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut user) {
-                                let err_msg = format!("Failed to read the user. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-
-                            image_request.user = Some(user);
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the user. The user field in the request should be a text field.";
-
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
-
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "cfg_scale" => match field.is_text() {
-                        true => {
-                            let mut cfg_scale = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut cfg_scale) {
-                                let err_msg = format!("Failed to read the cfg_config. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-
-                            match cfg_scale.parse::<f32>() {
-                                Ok(scale) => image_request.cfg_scale = Some(scale),
-                                Err(e) => {
-                                    let err_msg = format!(
-                                        "Failed to parse the number of images. Reason: {}",
-                                        e
-                                    );
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
-
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the cfg_config. The cfg_config field in the request should be a text field.";
-
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
-
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "sample_method" => match field.is_text() {
-                        true => {
-                            let mut sample_method = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut sample_method) {
-                                let err_msg = format!("Failed to read the sample_method. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-
-                            image_request.sample_method = Some(sample_method.as_str().into());
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the sample_method. The sample_method field in the request should be a text field.";
-
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
-
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "steps" => match field.is_text() {
-                        true => {
-                            let mut steps = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut steps) {
-                                let err_msg = format!("Failed to read the steps. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-
-                            match steps.parse::<usize>() {
-                                Ok(steps) => image_request.steps = Some(steps),
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse the steps. Reason: {}", e);
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
-
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the steps. The steps field in the request should be a text field.";
-
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
-
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "height" => match field.is_text() {
-                        true => {
-                            let mut height = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut height) {
-                                let err_msg = format!("Failed to read the height. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-
-                            match height.parse::<usize>() {
-                                Ok(height) => image_request.height = Some(height),
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse the height. Reason: {}", e);
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
-
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the height. The height field in the request should be a text field.";
-
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
-
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "width" => match field.is_text() {
-                        true => {
-                            let mut width = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut width) {
-                                let err_msg = format!("Failed to read the width. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-
-                            match width.parse::<usize>() {
-                                Ok(width) => image_request.width = Some(width),
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse the width. Reason: {}", e);
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
-
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the width. The width field in the request should be a text field.";
-
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
-
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "control_strength" => match field.is_text() {
-                        true => {
-                            let mut control_strength = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut control_strength) {
-                                let err_msg = format!("Failed to read the control_strength. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-
-                            match control_strength.parse::<f32>() {
-                                Ok(control_strength) => {
-                                    image_request.control_strength = Some(control_strength)
-                                }
-                                Err(e) => {
-                                    let err_msg = format!(
-                                        "Failed to parse the control_strength. Reason: {}",
-                                        e
-                                    );
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
-
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the control_strength. The control_strength field in the request should be a text field.";
-
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
-
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "control_image" => {
-                        let filename = match field.headers.filename {
-                            Some(filename) => filename,
-                            None => {
-                                let err_msg =
-                                    "Failed to upload the image file. The filename is not provided.";
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
-
-                        // get the image data
-                        let mut buffer = Vec::new();
-                        let size_in_bytes = match field.data.read_to_end(&mut buffer) {
-                            Ok(size_in_bytes) => size_in_bytes,
-                            Err(e) => {
-                                let err_msg = format!("Failed to read the image file. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
-
-                        // create a file id for the image file
-                        let id = format!("file_{}", uuid::Uuid::new_v4());
-
-                        // save the file
-                        let path = Path::new("archives");
-                        if !path.exists() {
-                            fs::create_dir(path).unwrap();
-                        }
-                        let file_path = path.join(&id);
-                        if !file_path.exists() {
-                            fs::create_dir(&file_path).unwrap();
-                        }
-                        let mut file = match File::create(file_path.join(&filename)) {
-                            Ok(file) => file,
-                            Err(e) => {
-                                let err_msg = format!(
-                                    "Failed to create archive document {}. {}",
-                                    &filename, e
-                                );
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
-                        file.write_all(&buffer[..]).unwrap();
-
-                        // log
-                        info!(target: "stdout", "file_id: {}, file_name: {}, size in bytes: {}", &id, &filename, size_in_bytes);
-
-                        let created_at =
-                            match SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
-                                Ok(n) => n.as_secs(),
-                                Err(_) => {
-                                    let err_msg = "Failed to get the current time.";
-
-                                    // log
-                                    error!(target: "stdout", "{}", err_msg);
-
-                                    return error::internal_server_error(err_msg);
-                                }
-                            };
-
-                        // create a file object
-                        image_request.control_image = Some(FileObject {
-                            id,
-                            bytes: size_in_bytes as u64,
-                            created_at,
-                            filename,
-                            object: "file".to_string(),
-                            purpose: "assistants".to_string(),
-                        });
-                    }
-                    "seed" => match field.is_text() {
-                        true => {
-                            let mut seed = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut seed) {
-                                let err_msg = format!("Failed to read the seed. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-
-                            match seed.parse::<i32>() {
-                                Ok(seed) => image_request.seed = Some(seed),
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse the seed. Reason: {}", e);
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
-
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the seed. The seed field in the request should be a text field.";
-
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
-
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "strength" => match field.is_text() {
-                        true => {
-                            let mut strength = String::new();
-
-                            if let Err(e) = field.data.read_to_string(&mut strength) {
-                                let err_msg = format!("Failed to read the strength. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-
-                            match strength.parse::<f32>() {
-                                Ok(strength) => {
-                                    image_request.strength = Some(strength);
-                                    info!(target: "stdout", "strength: {}", strength);
-                                }
-                                Err(e) => {
-                                    let err_msg =
-                                        format!("Failed to parse the strength. Reason: {}", e);
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
-
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the strength. The strength field in the request should be a text field.";
-
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
-
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    unsupported_field => {
-                        let err_msg = format!("Unsupported field: {}", unsupported_field);
+                }
+                Err(e) => {
+                    let err_msg = format!("Failed to serialize the loras. {}", e);
 
-                        // log
-                        error!(target: "stdout", "{}", &err_msg);
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                        return error::bad_request(err_msg);
-                    }
+                    error::internal_server_error(err_msg)
                 }
             }
+        }
+        Method::OPTIONS => {
+            let result = Response::builder()
+                .header("Access-Control-Allow-Origin", "*")
+                .header("Access-Control-Allow-Methods", "*")
+                .header("Access-Control-Allow-Headers", "*")
+                .header("Content-Type", "application/json")
+                .body(Body::empty());
 
-            // log
-            info!(target: "stdout", "image edit request: {:?}", &image_request);
-
-            // check if the user id is provided
-            if image_request.user.is_none() {
-                image_request.user = Some(gen_image_id())
-            };
-            let id = image_request.user.clone().unwrap();
-
-            // log user id
-            info!(target: "stdout", "user: {}", image_request.user.clone().unwrap());
-
-            match llama_core::images::image_edit(&mut image_request).await {
-                Ok(mut images_response) => {
-                    if Some(ResponseFormat::Url) == image_request.response_format {
-                        for image_object in images_response.data.iter_mut() {
-                            let segments: Vec<&str> =
-                                image_object.url.as_ref().unwrap().split("/").collect();
-                            match segments.as_slice() {
-                                [_, _, id, ..] => {
-                                    // get the socket address of request
-                                    let download_url_prefix = DOWNLOAD_URL_PREFIX.get().unwrap();
-
-                                    let host = match download_url_prefix.port() {
-                                        Some(port) => {
-                                            format!(
-                                                "{}:{}",
-                                                download_url_prefix.host_str().unwrap(),
-                                                port
-                                            )
-                                        }
-                                        None => download_url_prefix.host_str().unwrap().to_string(),
-                                    };
-
-                                    image_object.url = Some(format!(
-                                        "{}://{}/v1/files/download/{}",
-                                        download_url_prefix.scheme(),
-                                        host,
-                                        id
-                                    ))
-                                }
-                                _ => {
-                                    let err_msg =
-                                        "Failed to parse the url from the image response.";
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
-
-                                    return error::internal_server_error(err_msg);
-                                }
-                            }
-                        }
-                    }
-
-                    match serde_json::to_string(&images_response) {
-                        Ok(s) => {
-                            // return response
-                            let result = Response::builder()
-                                .header("Access-Control-Allow-Origin", "*")
-                                .header("Access-Control-Allow-Methods", "*")
-                                .header("Access-Control-Allow-Headers", "*")
-                                .header("Content-Type", "application/json")
-                                .header("user", id)
-                                .body(Body::from(s));
-                            match result {
-                                Ok(response) => response,
-                                Err(e) => {
-                                    let err_msg = e.to_string();
-
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
-
-                                    error::internal_server_error(err_msg)
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            let err_msg = format!(
-                                "Fail to serialize the `ListImagesResponse` instance. {}",
-                                e
-                            );
-
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
-
-                            error::internal_server_error(err_msg)
-                        }
-                    }
-                }
+            match result {
+                Ok(response) => response,
                 Err(e) => {
-                    let err_msg = format!("Failed to get image edit result. Reason: {}", e);
+                    let err_msg = e.to_string();
 
                     // log
                     error!(target: "stdout", "{}", &err_msg);
@@ -1702,374 +1401,406 @@ pub(crate) async fn image_edit_handler(req: Request<Body>) -> Response<Body> {
         _ => error::method_not_allowed(req.method()),
     };
 
-    // log
-    info!(target: "stdout", "Send the image edit response.");
+    info!(target: "stdout", "Send the loras response");
 
     res
 }
 
-pub(crate) async fn image_variation_handler(req: Request<Body>) -> Response<Body> {
+/// Describe the generation queue.
+///
+/// - `GET /v1/queue`: Retrieve the status of the generation queue.
+///
+pub(crate) async fn queue_handler(req: Request<Body>) -> Response<Body> {
     // log
-    info!(target: "stdout", "Handling the coming image variation request");
+    info!(target: "stdout", "Handling the coming queue request");
 
-    if req.method().eq(&hyper::http::Method::OPTIONS) {
-        let result = Response::builder()
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "*")
-            .header("Access-Control-Allow-Headers", "*")
-            .header("Content-Type", "application/json")
-            .body(Body::empty());
+    let res = match *req.method() {
+        Method::GET => match serde_json::to_string(&queue::status()) {
+            Ok(s) => {
+                // return response
+                let result = Response::builder()
+                    .header("Access-Control-Allow-Origin", "*")
+                    .header("Access-Control-Allow-Methods", "*")
+                    .header("Access-Control-Allow-Headers", "*")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(s));
+
+                match result {
+                    Ok(response) => response,
+                    Err(e) => {
+                        let err_msg = e.to_string();
 
-        match result {
-            Ok(response) => return response,
+                        // log
+                        error!(target: "stdout", "{}", &err_msg);
+
+                        error::internal_server_error(err_msg)
+                    }
+                }
+            }
             Err(e) => {
-                let err_msg = e.to_string();
+                let err_msg = format!("Failed to serialize the queue status. {}", e);
 
                 // log
                 error!(target: "stdout", "{}", &err_msg);
 
-                return error::internal_server_error(err_msg);
+                error::internal_server_error(err_msg)
             }
-        }
-    }
-
-    let res = match *req.method() {
-        Method::POST => {
-            let boundary = "boundary=";
-
-            let boundary = req.headers().get("content-type").and_then(|ct| {
-                let ct = ct.to_str().ok()?;
-                let idx = ct.find(boundary)?;
-                Some(ct[idx + boundary.len()..].to_string())
-            });
-
-            let req_body = req.into_body();
-            let body_bytes = match to_bytes(req_body).await {
-                Ok(body_bytes) => body_bytes,
-                Err(e) => {
-                    let err_msg = format!("Fail to read buffer from request body. {}", e);
-
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
-
-                    return error::internal_server_error(err_msg);
-                }
-            };
-
-            let cursor = Cursor::new(body_bytes.to_vec());
-
-            let mut multipart = Multipart::with_body(cursor, boundary.unwrap());
-
-            let mut image_request = ImageVariationRequest::default();
-            while let ReadEntryResult::Entry(mut field) = multipart.read_entry_mut() {
-                match &*field.headers.name {
-                    "image" => {
-                        let filename = match field.headers.filename {
-                            Some(filename) => filename,
-                            None => {
-                                let err_msg =
-                                    "Failed to upload the image file. The filename is not provided.";
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
-
-                        // get the image data
-                        let mut buffer = Vec::new();
-                        let size_in_bytes = match field.data.read_to_end(&mut buffer) {
-                            Ok(size_in_bytes) => size_in_bytes,
-                            Err(e) => {
-                                let err_msg = format!("Failed to read the image file. {}", e);
-
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
-
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
-
-                        // create a file id for the image file
-                        let id = format!("file_{}", uuid::Uuid::new_v4());
-
-                        // save the file
-                        let path = Path::new("archives");
-                        if !path.exists() {
-                            fs::create_dir(path).unwrap();
-                        }
-                        let file_path = path.join(&id);
-                        if !file_path.exists() {
-                            fs::create_dir(&file_path).unwrap();
-                        }
-                        let mut file = match File::create(file_path.join(&filename)) {
-                            Ok(file) => file,
-                            Err(e) => {
-                                let err_msg = format!(
-                                    "Failed to create archive document {}. {}",
-                                    &filename, e
-                                );
+        },
+        Method::OPTIONS => {
+            let result = Response::builder()
+                .header("Access-Control-Allow-Origin", "*")
+                .header("Access-Control-Allow-Methods", "*")
+                .header("Access-Control-Allow-Headers", "*")
+                .header("Content-Type", "application/json")
+                .body(Body::empty());
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+            match result {
+                Ok(response) => response,
+                Err(e) => {
+                    let err_msg = e.to_string();
 
-                                return error::internal_server_error(err_msg);
-                            }
-                        };
-                        file.write_all(&buffer[..]).unwrap();
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                        // log
-                        info!(target: "stdout", "file_id: {}, file_name: {}, size in bytes: {}", &id, &filename, size_in_bytes);
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        _ => error::method_not_allowed(req.method()),
+    };
 
-                        let created_at =
-                            match SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
-                                Ok(n) => n.as_secs(),
-                                Err(_) => {
-                                    let err_msg = "Failed to get the current time.";
+    info!(target: "stdout", "Send the queue response");
 
-                                    // log
-                                    error!(target: "stdout", "{}", err_msg);
+    res
+}
 
-                                    return error::internal_server_error(err_msg);
-                                }
-                            };
+/// Run the image request once its turn comes and report the progress as server-sent events.
+///
+/// The stream emits the following events:
+/// - `queued`: the position of the request in the generation queue, sent whenever it changes.
+/// - `started`: the request left the queue and is being run.
+/// - `completed`: the request finished, with the generated images.
+/// - `failed`: the request finished with an error, sent in the JSON body of the error responses.
+fn stream_response(
+    ticket: queue::Ticket,
+    user: String,
+    generation: impl Future<Output = Result<ListImagesResponse, String>> + Send + 'static,
+) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        // report the queue position until the request leaves the queue
+        let mut last_position = None;
+        loop {
+            if let Some((position, estimated_wait)) = queue::position(ticket.id()) {
+                if last_position != Some(position) {
+                    last_position = Some(position);
+
+                    let data = serde_json::json!({
+                        "queue_position": position,
+                        "estimated_wait": estimated_wait,
+                    });
+                    if send_event(&mut sender, "queued", data).await.is_err() {
+                        // log
+                        warn!(target: "stdout", "The client left the event stream while queued.");
 
-                        // create a file object
-                        image_request.image = FileObject {
-                            id,
-                            bytes: size_in_bytes as u64,
-                            created_at,
-                            filename,
-                            object: "file".to_string(),
-                            purpose: "assistants".to_string(),
-                        };
+                        return;
                     }
-                    "model" => match field.is_text() {
-                        true => {
-                            let mut model = String::new();
+                }
+            }
 
-                            if let Err(e) = field.data.read_to_string(&mut model) {
-                                let err_msg = format!("Failed to read the model. {}", e);
+            if tokio::time::timeout(QUEUE_POLL_INTERVAL, ticket.wait_turn())
+                .await
+                .is_ok()
+            {
+                break;
+            }
+        }
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+        if send_event(&mut sender, "started", serde_json::json!({}))
+            .await
+            .is_err()
+        {
+            // log
+            warn!(target: "stdout", "The client left the event stream before the generation started.");
 
-                                return error::internal_server_error(err_msg);
-                            }
+            return;
+        }
 
-                            image_request.model = model;
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the model name. The model field in the request should be a text field.";
+        // let the event reach the client before the generation occupies the runtime
+        tokio::task::yield_now().await;
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+        let (event, data) = match generation.await {
+            Ok(images_response) => match serde_json::to_value(&images_response) {
+                Ok(data) => ("completed", data),
+                Err(e) => {
+                    let err_msg =
+                        format!("Fail to serialize the `ListImagesResponse` instance. {}", e);
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "n" => match field.is_text() {
-                        true => {
-                            let mut n = String::new();
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                            if let Err(e) = field.data.read_to_string(&mut n) {
-                                let err_msg = format!("Failed to read the number of images. {}", e);
+                    (
+                        "failed",
+                        error::error_body("server_error", err_msg, None, None),
+                    )
+                }
+            },
+            Err(err_msg) => (
+                "failed",
+                error::error_body("server_error", err_msg, None, Some("backend_error")),
+            ),
+        };
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+        // release the queue before sending the last event
+        drop(ticket);
 
-                                return error::internal_server_error(err_msg);
-                            }
+        if send_event(&mut sender, event, data).await.is_err() {
+            // log
+            warn!(target: "stdout", "The client left the event stream before the generation finished.");
+        }
+    });
+
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .header("user", user)
+        .body(body);
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
 
-                            match n.parse::<u64>() {
-                                Ok(n) => image_request.n = Some(n),
-                                Err(e) => {
-                                    let err_msg = format!(
-                                        "Failed to parse the number of images. Reason: {}",
-                                        e
-                                    );
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+            error::internal_server_error(err_msg)
+        }
+    }
+}
 
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                            "Failed to get the number of images. The n field in the request should be a text field.";
+/// Send a server-sent event.
+async fn send_event(
+    sender: &mut hyper::body::Sender,
+    event: &str,
+    data: serde_json::Value,
+) -> Result<(), hyper::Error> {
+    let message = format!("event: {}\ndata: {}\n\n", event, data);
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+    sender.send_data(message.into()).await
+}
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "response_format" => match field.is_text() {
-                        true => {
-                            let mut response_format = String::new();
+/// Build the error response for a request rejected by the full generation queue.
+fn queue_unavailable(e: queue::QueueError) -> Response<Body> {
+    let queue::QueueError::Full {
+        max_size,
+        estimated_wait,
+    } = e;
+    let err_msg = format!(
+        "The generation queue is full ({} requests waiting). Please retry later.",
+        max_size
+    );
 
-                            if let Err(e) = field.data.read_to_string(&mut response_format) {
-                                let err_msg = format!("Failed to read the response format. {}", e);
+    // log
+    error!(target: "stdout", "{}", &err_msg);
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+    let mut response = ServerError::Unavailable(err_msg).into_response();
+    if let Some(estimated_wait) = estimated_wait {
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(estimated_wait.max(1)));
+    }
 
-                                return error::internal_server_error(err_msg);
-                            }
+    response
+}
 
-                            match response_format.parse::<ResponseFormat>() {
-                                Ok(format) => image_request.response_format = Some(format),
-                                Err(e) => {
-                                    let err_msg = format!(
-                                        "Failed to parse the response format. Reason: {}",
-                                        e
-                                    );
+fn preset_error(e: presets::PresetError) -> Response<Body> {
+    let err_msg = e.to_string();
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+    // log
+    error!(target: "stdout", "{}", &err_msg);
+
+    let e = match e {
+        presets::PresetError::InvalidName(_) => ServerError::InvalidRequest {
+            message: err_msg,
+            param: None,
+            code: Some("invalid_value".to_string()),
+        },
+        presets::PresetError::NotFound(_) => ServerError::NotFound {
+            message: err_msg,
+            param: None,
+            code: Some("preset_not_found".to_string()),
+        },
+        presets::PresetError::Save(_) => ServerError::Operation(err_msg),
+    };
 
-                                    return error::bad_request(err_msg);
-                                }
-                            }
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the response format. The response format field in the request should be a text field.";
+    e.into_response()
+}
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+fn json_response(
+    s: Result<String, serde_json::Error>,
+    status: hyper::StatusCode,
+) -> Response<Body> {
+    match s {
+        Ok(s) => {
+            // return response
+            let result = Response::builder()
+                .header("Access-Control-Allow-Origin", "*")
+                .header("Access-Control-Allow-Methods", "*")
+                .header("Access-Control-Allow-Headers", "*")
+                .header("Content-Type", "application/json")
+                .status(status)
+                .body(Body::from(s));
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "size" => match field.is_text() {
-                        true => {
-                            let mut size = String::new();
+            match result {
+                Ok(response) => response,
+                Err(e) => {
+                    let err_msg = e.to_string();
 
-                            if let Err(e) = field.data.read_to_string(&mut size) {
-                                let err_msg = format!("Failed to read the size. {}", e);
+                    // log
+                    error!(target: "stdout", "{}", &err_msg);
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+                    error::internal_server_error(err_msg)
+                }
+            }
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to serialize the response. {}", e);
 
-                                return error::internal_server_error(err_msg);
-                            }
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                            image_request.size = Some(size);
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the size. The size field in the request should be a text field.";
+            error::internal_server_error(err_msg)
+        }
+    }
+}
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+/// Whether the `model` field of an image request names the served model. An empty field selects
+/// the served model.
+fn is_served_model(model: &str) -> bool {
+    model.is_empty() || models::model_name().as_deref() == Some(model)
+}
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    "user" => match field.is_text() {
-                        true => {
-                            let mut user = String::new();
+fn model_not_found(model_name: &str) -> Response<Body> {
+    let err_msg = format!(
+        "The model `{}` is not found. Available models: {}",
+        model_name,
+        models::model_name().unwrap_or_default()
+    );
 
-                            if let Err(e) = field.data.read_to_string(&mut user) {
-                                let err_msg = format!("Failed to read the user. {}", e);
+    // log
+    error!(target: "stdout", "{}", &err_msg);
 
-                                // log
-                                error!(target: "stdout", "{}", &err_msg);
+    ServerError::NotFound {
+        message: err_msg,
+        param: Some("model".to_string()),
+        code: Some("model_not_found".to_string()),
+    }
+    .into_response()
+}
 
-                                return error::internal_server_error(err_msg);
-                            }
+fn cancel_job(job_id: &str, owner: Option<&str>) -> Response<Body> {
+    match jobs::cancel_job(job_id, owner) {
+        Ok(status) => {
+            // log
+            info!(target: "stdout", "job_id: {}, status: {:?}", job_id, status);
 
-                            image_request.user = Some(user);
-                        }
-                        false => {
-                            let err_msg =
-                                "Failed to get the user. The user field in the request should be a text field.";
+            job_response(job_id, owner, hyper::StatusCode::OK)
+        }
+        Err(jobs::CancelError::NotFound) => {
+            let err_msg = format!("The job {} is not found.", job_id);
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                            return error::internal_server_error(err_msg);
-                        }
-                    },
-                    _ => unimplemented!("unknown field"),
-                }
-            }
+            error::not_found(err_msg)
+        }
+        Err(jobs::CancelError::Running) => {
+            let err_msg = format!(
+                "The job {} cannot be cancelled. It is running, and the backend cannot be interrupted.",
+                job_id
+            );
 
             // log
-            info!(target: "stdout", "image variation request: {:?}", &image_request);
-
-            // check if the user id is provided
-            if image_request.user.is_none() {
-                image_request.user = Some(gen_image_id())
-            };
-            let id = image_request.user.clone().unwrap();
+            error!(target: "stdout", "{}", &err_msg);
 
-            // log user id
-            info!(target: "stdout", "user: {}", image_request.user.clone().unwrap());
+            error::conflict(err_msg)
+        }
+        Err(jobs::CancelError::Finished(status)) => {
+            let err_msg = format!(
+                "The job {} cannot be cancelled. It already finished with the status {:?}.",
+                job_id, status
+            );
 
-            match llama_core::images::image_variation(&mut image_request).await {
-                Ok(images_response) => {
-                    match serde_json::to_string(&images_response) {
-                        Ok(s) => {
-                            // return response
-                            let result = Response::builder()
-                                .header("Access-Control-Allow-Origin", "*")
-                                .header("Access-Control-Allow-Methods", "*")
-                                .header("Access-Control-Allow-Headers", "*")
-                                .header("Content-Type", "application/json")
-                                .header("user", id)
-                                .body(Body::from(s));
-                            match result {
-                                Ok(response) => response,
-                                Err(e) => {
-                                    let err_msg = e.to_string();
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                                    // log
-                                    error!(target: "stdout", "{}", &err_msg);
+            error::conflict(err_msg)
+        }
+    }
+}
 
-                                    error::internal_server_error(err_msg)
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            let err_msg = format!(
-                                "Fail to serialize the `ListImagesResponse` instance. {}",
-                                e
-                            );
+/// Build the response describing the job with the given id, if visible to the owner key, if any.
+fn job_response(job_id: &str, owner: Option<&str>, status: hyper::StatusCode) -> Response<Body> {
+    let s = match jobs::job_to_json(job_id, owner) {
+        Some(Ok(s)) => s,
+        Some(Err(e)) => {
+            let err_msg = format!("Failed to serialize the job {}. {}", job_id, e);
 
-                            // log
-                            error!(target: "stdout", "{}", &err_msg);
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                            error::internal_server_error(err_msg)
-                        }
-                    }
-                }
-                Err(e) => {
-                    let err_msg = format!("Failed to get image edit result. Reason: {}", e);
+            return error::internal_server_error(err_msg);
+        }
+        None => {
+            let err_msg = format!("The job {} is not found.", job_id);
 
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
+            // log
+            error!(target: "stdout", "{}", &err_msg);
 
-                    error::internal_server_error(err_msg)
-                }
-            }
+            return error::not_found(err_msg);
         }
-        _ => error::method_not_allowed(req.method()),
     };
 
-    // log
-    info!(target: "stdout", "Send the image variation response.");
+    // return response
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .status(status)
+        .body(Body::from(s));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
 
-    res
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
 }
 
-/// Download, retrieve and delete a file, or list all files.
+/// Check if the query string of the request sets the given flag to `true`.
+fn query_flag(req: &Request<Body>, name: &str) -> bool {
+    match req.uri().query() {
+        Some(query) => url::form_urlencoded::parse(query.as_bytes()).any(|(key, value)| {
+            key == name && (value.is_empty() || value == "true" || value == "1")
+        }),
+        None => false,
+    }
+}
+
+/// Upload, download, retrieve and delete a file, or list all files.
 ///
+/// - `POST /v1/files`: Upload a file.
 /// - `GET /v1/files`: List all files.
 /// - `GET /v1/files/{file_id}`: Retrieve a file by id.
 /// - `GET /v1/files/{file_id}/content`: Retrieve the content of a file by id.
@@ -2089,41 +1820,54 @@ pub(crate) async fn files_handler(req: Request<Body>) -> Response<Body> {
         match segments.as_slice() {
             ["", "v1", "files"] => list_files(),
             ["", "v1", "files", file_id, "content"] => {
-                if !file_id.starts_with("file_") {
-                    let err_msg = format!("unsupported uri path: {}", uri_path);
-
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
-
-                    return error::internal_server_error(err_msg);
+                if let Err(e) = form::check_file_id("file_id", file_id) {
+                    return e.into_response();
                 }
 
                 retrieve_file_content(file_id)
             }
             ["", "v1", "files", file_id] => {
-                if !file_id.starts_with("file_") {
-                    let err_msg = format!("unsupported uri path: {}", uri_path);
-
-                    // log
-                    error!(target: "stdout", "{}", &err_msg);
-
-                    return error::internal_server_error(err_msg);
+                if let Err(e) = form::check_file_id("file_id", file_id) {
+                    return e.into_response();
                 }
 
                 retrieve_file(file_id)
             }
-            ["", "v1", "files", "download", file_id, _file_name] => download_file(file_id),
+            ["", "v1", "files", "download", file_id, _file_name] => {
+                if let Err(e) = form::check_file_id("file_id", file_id) {
+                    return e.into_response();
+                }
+
+                download_file(file_id)
+            }
             _ => {
                 let err_msg = format!("unsupported uri path: {}", uri_path);
 
                 // log
                 error!(target: "stdout", "{}", &err_msg);
 
-                error::internal_server_error(err_msg)
+                error::invalid_endpoint(err_msg)
             }
         }
+    } else if req.method() == Method::POST {
+        let uri_path = req.uri().path().trim_end_matches('/');
+
+        if uri_path != "/v1/files" {
+            let err_msg = format!("unsupported uri path: {}", uri_path);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::invalid_endpoint(err_msg);
+        }
+
+        upload_file(req).await
     } else if req.method() == Method::DELETE {
         let id = req.uri().path().trim_start_matches("/v1/files/");
+        if let Err(e) = form::check_file_id("file_id", id) {
+            return e.into_response();
+        }
+
         let status = match llama_core::files::remove_file(id) {
             Ok(status) => status,
             Err(e) => {
@@ -2195,12 +1939,7 @@ pub(crate) async fn files_handler(req: Request<Body>) -> Response<Body> {
             }
         }
     } else {
-        let err_msg = "Invalid HTTP Method.";
-
-        // log
-        error!(target: "stdout", "{}", &err_msg);
-
-        error::internal_server_error(err_msg)
+        error::method_not_allowed(req.method())
     };
 
     info!(target: "stdout", "Send the files response");
@@ -2208,6 +1947,55 @@ pub(crate) async fn files_handler(req: Request<Body>) -> Response<Body> {
     res
 }
 
+async fn upload_file(req: Request<Body>) -> Response<Body> {
+    let upload_request = match form::read_multipart(req).await {
+        Ok(form) => match form.extract::<form::FileUploadRequest>() {
+            Ok((upload_request, _)) => upload_request,
+            Err(e) => return e.into_response(),
+        },
+        Err(e) => return e.into_response(),
+    };
+
+    let file_object = match upload_request.save() {
+        Ok(file_object) => file_object,
+        Err(e) => return e.into_response(),
+    };
+    metrics::observe_upload(file_object.bytes);
+
+    // serialize file object
+    let s = match serde_json::to_string(&file_object) {
+        Ok(s) => s,
+        Err(e) => {
+            let err_msg = format!("Failed to serialize file object. {}", e);
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            return error::internal_server_error(err_msg);
+        }
+    };
+
+    // return response
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .body(Body::from(s));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
 fn list_files() -> Response<Body> {
     match llama_core::files::list_files() {
         Ok(file_objects) => {
@@ -2370,7 +2158,7 @@ fn download_file(id: impl AsRef<str>) -> Response<Body> {
                     // log
                     error!(target: "stdout", "{}", &err_msg);
 
-                    return error::internal_server_error(err_msg);
+                    return error::unsupported_media_type(err_msg);
                 }
             };
             let content_disposition = format!("attachment; filename={}", filename);
@@ -0,0 +1,341 @@
+use super::form::{parse_size, FieldValue, FormError};
+use crate::{
+    limits,
+    models::{self, GenerationDefaults},
+};
+use endpoints::images::{ImageCreateRequest, ImageEditRequest, ImageVariationRequest};
+
+/// Sampling methods supported by the backend.
+const SAMPLE_METHODS: [&str; 10] = [
+    "euler",
+    "euler_a",
+    "heun",
+    "dpm2",
+    "dpm++2s_a",
+    "dpm++2m",
+    "dpm++2mv2",
+    "ipndm",
+    "ipndm_v",
+    "lcm",
+];
+
+/// Maximum number of images generated by a request.
+const MAX_N: u64 = 10;
+
+/// Maximum number of sample steps.
+const MAX_STEPS: usize = 150;
+
+/// Maximum classifier-free guidance scale.
+const MAX_CFG_SCALE: f32 = 30.0;
+
+/// Minimum height and width of the images.
+const MIN_SIZE: usize = 64;
+
+/// Maximum pixel count of the images, i.e. 2048x2048.
+const MAX_PIXELS: usize = 2048 * 2048;
+
+/// Check the parameters of an image generation request. The sampling method is
+/// checked by the name the client sent, since the backend maps an unknown name to its default.
+pub(crate) fn validate_create(
+    request: &ImageCreateRequest,
+    sample_method: Option<&str>,
+) -> Result<(), FormError> {
+    check_n(request.n)?;
+    check_sample_method(sample_method)?;
+    check_steps(request.steps)?;
+    check_cfg_scale(request.cfg_scale)?;
+    check_unit_interval("control_strength", request.control_strength)?;
+    check_size(request.height, request.width, models::is_diffusion_model())?;
+
+    Ok(())
+}
+
+/// Check the parameters of an image edit request. The sampling method is
+/// checked by the name the client sent, as for [`validate_create`].
+pub(crate) fn validate_edit(
+    request: &ImageEditRequest,
+    sample_method: Option<&str>,
+) -> Result<(), FormError> {
+    check_n(request.n)?;
+    check_sample_method(sample_method)?;
+    check_steps(request.steps)?;
+    check_cfg_scale(request.cfg_scale)?;
+    check_unit_interval("control_strength", request.control_strength)?;
+    check_unit_interval("strength", request.strength)?;
+    check_size(request.height, request.width, models::is_diffusion_model())?;
+
+    Ok(())
+}
+
+/// Check the parameters of an image variation request.
+pub(crate) fn validate_variation(request: &ImageVariationRequest) -> Result<(), FormError> {
+    check_n(request.n)?;
+
+    if let Some(size) = &request.size {
+        let (height, width) = parse_size("size", FieldValue::Text(size.clone()))?;
+        check_size(Some(height), Some(width), models::is_diffusion_model())?;
+    }
+
+    Ok(())
+}
+
+/// Check the parameters of a preset or of the defaults of a model, which are applied to the
+/// image requests. `diffusion_model` tells whether the sizes are meant for a standalone diffusion
+/// model, since the defaults of the model are checked before the model is loaded.
+pub(crate) fn validate_defaults(
+    defaults: &GenerationDefaults,
+    diffusion_model: bool,
+) -> Result<(), FormError> {
+    check_n(defaults.n)?;
+    check_sample_method(defaults.sample_method.as_deref())?;
+    check_steps(defaults.steps)?;
+    check_cfg_scale(defaults.cfg_scale)?;
+    check_unit_interval("control_strength", defaults.control_strength)?;
+    check_unit_interval("strength", defaults.strength)?;
+    check_size(defaults.height, defaults.width, diffusion_model)?;
+
+    Ok(())
+}
+
+fn check_n(n: Option<u64>) -> Result<(), FormError> {
+    match n {
+        Some(n) if n == 0 || n > MAX_N => Err(FormError::invalid_field(
+            "n",
+            format!("The value should be between 1 and {}.", MAX_N),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_sample_method(sample_method: Option<&str>) -> Result<(), FormError> {
+    match sample_method.map(|sample_method| sample_method.trim()) {
+        Some(sample_method) if !SAMPLE_METHODS.contains(&sample_method) => {
+            Err(FormError::invalid_field(
+                "sample_method",
+                format!(
+                    "Unknown sampling method `{}`. Supported sampling methods: {}",
+                    sample_method,
+                    SAMPLE_METHODS.join(", ")
+                ),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_steps(steps: Option<usize>) -> Result<(), FormError> {
+    match steps {
+        Some(steps) if steps == 0 || steps > MAX_STEPS => Err(FormError::invalid_field(
+            "steps",
+            format!("The value should be between 1 and {}.", MAX_STEPS),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_cfg_scale(cfg_scale: Option<f32>) -> Result<(), FormError> {
+    match cfg_scale {
+        Some(cfg_scale) if !(0.0..=MAX_CFG_SCALE).contains(&cfg_scale) => {
+            Err(FormError::invalid_field(
+                "cfg_scale",
+                format!("The value should be between 0 and {}.", MAX_CFG_SCALE),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_unit_interval(name: &str, value: Option<f32>) -> Result<(), FormError> {
+    match value {
+        Some(value) if !(0.0..=1.0).contains(&value) => Err(FormError::invalid_field(
+            name,
+            "The value should be between 0 and 1.",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Check the image size against the kind of model: full models, e.g. SD 1.x and SDXL, take
+/// multiples of 8, and standalone diffusion models, e.g. Flux and SD3, take multiples of 64.
+fn check_size(
+    height: Option<usize>,
+    width: Option<usize>,
+    diffusion_model: bool,
+) -> Result<(), FormError> {
+    let (multiple, kind) = match diffusion_model {
+        true => (64, "a standalone diffusion model"),
+        false => (8, "a full model"),
+    };
+
+    for (name, value) in [("height", height), ("width", width)] {
+        if let Some(value) = value {
+            if value < MIN_SIZE || value % multiple != 0 {
+                return Err(FormError::invalid_field(
+                    name,
+                    format!(
+                        "The value should be a multiple of {} of at least {} for {}.",
+                        multiple, MIN_SIZE, kind
+                    ),
+                ));
+            }
+        }
+    }
+
+    let height = height.unwrap_or(limits::DEFAULT_SIZE as usize);
+    let width = width.unwrap_or(limits::DEFAULT_SIZE as usize);
+    if height.saturating_mul(width) > MAX_PIXELS {
+        return Err(FormError::invalid_field(
+            "size",
+            format!(
+                "The image size {}x{} exceeds the maximum of {} pixels.",
+                height, width, MAX_PIXELS
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_are_checked_against_the_request_bounds() {
+        let defaults = GenerationDefaults {
+            n: Some(2),
+            steps: Some(4),
+            cfg_scale: Some(1.0),
+            sample_method: Some("euler".to_string()),
+            height: Some(1024),
+            width: Some(1024),
+            strength: Some(0.75),
+            ..Default::default()
+        };
+        assert!(validate_defaults(&defaults, true).is_ok());
+
+        for (defaults, name) in [
+            (
+                GenerationDefaults {
+                    steps: Some(0),
+                    ..Default::default()
+                },
+                "steps",
+            ),
+            (
+                GenerationDefaults {
+                    sample_method: Some("ddim".to_string()),
+                    ..Default::default()
+                },
+                "sample_method",
+            ),
+            (
+                GenerationDefaults {
+                    strength: Some(1.5),
+                    ..Default::default()
+                },
+                "strength",
+            ),
+        ] {
+            match validate_defaults(&defaults, false) {
+                Err(FormError::InvalidField { name: field, .. }) => assert_eq!(field, name),
+                result => panic!("unexpected result for `{}`: {:?}", name, result),
+            }
+        }
+    }
+
+    #[test]
+    fn default_sizes_depend_on_the_kind_of_model() {
+        let defaults = GenerationDefaults {
+            height: Some(520),
+            width: Some(512),
+            ..Default::default()
+        };
+
+        assert!(validate_defaults(&defaults, false).is_ok());
+        assert!(matches!(
+            validate_defaults(&defaults, true),
+            Err(FormError::InvalidField { name, .. }) if name == "height"
+        ));
+    }
+
+    /// Return the name of the field rejected by the check, if any.
+    fn rejected(result: Result<(), FormError>) -> Option<String> {
+        match result {
+            Ok(()) => None,
+            Err(FormError::InvalidField { name, .. }) => Some(name),
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn the_bounds_are_inclusive() {
+        for n in [None, Some(1), Some(MAX_N)] {
+            assert_eq!(rejected(check_n(n)), None);
+        }
+        for n in [Some(0), Some(MAX_N + 1)] {
+            assert_eq!(rejected(check_n(n)).as_deref(), Some("n"));
+        }
+
+        for steps in [None, Some(1), Some(MAX_STEPS)] {
+            assert_eq!(rejected(check_steps(steps)), None);
+        }
+        for steps in [Some(0), Some(MAX_STEPS + 1)] {
+            assert_eq!(rejected(check_steps(steps)).as_deref(), Some("steps"));
+        }
+
+        for cfg_scale in [None, Some(0.0), Some(7.5), Some(MAX_CFG_SCALE)] {
+            assert_eq!(rejected(check_cfg_scale(cfg_scale)), None);
+        }
+        for cfg_scale in [Some(-0.5), Some(MAX_CFG_SCALE + 0.5), Some(f32::NAN)] {
+            assert_eq!(
+                rejected(check_cfg_scale(cfg_scale)).as_deref(),
+                Some("cfg_scale")
+            );
+        }
+
+        for strength in [Some(0.0), Some(1.0)] {
+            assert_eq!(rejected(check_unit_interval("strength", strength)), None);
+        }
+        assert_eq!(
+            rejected(check_unit_interval("strength", Some(1.01))).as_deref(),
+            Some("strength")
+        );
+    }
+
+    #[test]
+    fn sample_methods_are_checked_by_name() {
+        for sample_method in [None, Some("euler_a"), Some(" lcm ")] {
+            assert_eq!(rejected(check_sample_method(sample_method)), None);
+        }
+        assert_eq!(
+            rejected(check_sample_method(Some("ddim"))).as_deref(),
+            Some("sample_method")
+        );
+    }
+
+    #[test]
+    fn sizes_are_checked_against_the_kind_of_model_and_the_pixel_count() {
+        assert_eq!(rejected(check_size(None, None, false)), None);
+        assert_eq!(rejected(check_size(Some(512), Some(768), false)), None);
+        assert_eq!(rejected(check_size(Some(1024), Some(576), true)), None);
+        assert_eq!(rejected(check_size(Some(2048), Some(2048), true)), None);
+
+        assert_eq!(
+            rejected(check_size(Some(516), Some(512), false)).as_deref(),
+            Some("height")
+        );
+        assert_eq!(
+            rejected(check_size(Some(512), Some(56), false)).as_deref(),
+            Some("width")
+        );
+        assert_eq!(
+            rejected(check_size(Some(512), Some(520), true)).as_deref(),
+            Some("width")
+        );
+        assert_eq!(
+            rejected(check_size(Some(2048), Some(2112), true)).as_deref(),
+            Some("size")
+        );
+    }
+}
@@ -0,0 +1,395 @@
+use crate::{
+    error::ServerError,
+    models::{GenerationDefaults, ModelConfig},
+    utils::LogLevel,
+    TaskType,
+};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+/// Settings loaded from the `--config` file. Each setting has the name of the matching CLI option,
+/// written with `_` or `-`. The `models` list can define the model in place of the model settings.
+#[derive(Debug, Default)]
+pub(crate) struct FileConfig {
+    pub(crate) model_name: Option<String>,
+    pub(crate) model: Option<String>,
+    pub(crate) diffusion_model: Option<String>,
+    pub(crate) vae: Option<String>,
+    pub(crate) clip_l: Option<String>,
+    pub(crate) t5xxl: Option<String>,
+    pub(crate) lora_model_dir: Option<String>,
+    pub(crate) control_net: Option<String>,
+    pub(crate) control_net_cpu: Option<bool>,
+    pub(crate) threads: Option<i32>,
+    pub(crate) clip_on_cpu: Option<bool>,
+    pub(crate) vae_on_cpu: Option<bool>,
+    pub(crate) task: Option<TaskType>,
+    pub(crate) socket_addr: Option<SocketAddr>,
+    pub(crate) port: Option<u16>,
+    pub(crate) download_url_prefix: Option<String>,
+    pub(crate) max_queue_size: Option<usize>,
+    pub(crate) presets: Option<PathBuf>,
+    pub(crate) api_keys: Option<PathBuf>,
+    pub(crate) usage_file: Option<PathBuf>,
+    pub(crate) public_routes: Option<Vec<String>>,
+    /// The API key, overridden by the `API_KEY` environment variable.
+    pub(crate) api_key: Option<String>,
+    /// The log level, overridden by the `LLAMA_LOG` environment variable.
+    pub(crate) log_level: Option<LogLevel>,
+    /// Default generation parameters of the model set by the options.
+    pub(crate) defaults: Option<GenerationDefaults>,
+    /// The model, if defined by the `models` list. The list holds at most one model, since the
+    /// backend serves a single model per process.
+    pub(crate) models: Vec<ModelConfig>,
+}
+
+/// Load the TOML or YAML configuration file, chosen by the file extension.
+///
+/// Every unknown key and every invalid value is reported in the returned error.
+pub(crate) fn load_config(path: impl AsRef<Path>) -> Result<FileConfig, ServerError> {
+    let path = path.as_ref();
+
+    let text = fs::read_to_string(path).map_err(|e| {
+        ServerError::ArgumentError(format!(
+            "Failed to read the config file {}. {}",
+            path.display(),
+            e
+        ))
+    })?;
+
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase());
+    let value: Result<Value, String> = match extension.as_deref() {
+        Some("toml") => toml::from_str(&text).map_err(|e| e.to_string()),
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&text).map_err(|e| e.to_string()),
+        _ => {
+            return Err(ServerError::ArgumentError(format!(
+                "Unsupported config file {}. The file extension should be `.toml`, `.yaml` or `.yml`.",
+                path.display()
+            )));
+        }
+    };
+    let value = value.map_err(|e| {
+        ServerError::ArgumentError(format!(
+            "Failed to parse the config file {}. {}",
+            path.display(),
+            e
+        ))
+    })?;
+
+    let mut errors = Vec::new();
+    let config = parse_config(value, &mut errors);
+
+    if !errors.is_empty() {
+        return Err(ServerError::ArgumentError(format!(
+            "Invalid config file {}:\n  - {}",
+            path.display(),
+            errors.join("\n  - ")
+        )));
+    }
+
+    Ok(config)
+}
+
+fn parse_config(value: Value, errors: &mut Vec<String>) -> FileConfig {
+    let mut config = FileConfig::default();
+
+    let map = match as_table(value, "", errors) {
+        Some(map) => map,
+        None => return config,
+    };
+
+    for (key, value) in map {
+        match normalize_key(&key).as_str() {
+            "model_name" => config.model_name = check(&key, value, errors),
+            "model" => config.model = check(&key, value, errors),
+            "diffusion_model" => config.diffusion_model = check(&key, value, errors),
+            "vae" => config.vae = check(&key, value, errors),
+            "clip_l" => config.clip_l = check(&key, value, errors),
+            "t5xxl" => config.t5xxl = check(&key, value, errors),
+            "lora_model_dir" => config.lora_model_dir = check(&key, value, errors),
+            "control_net" => config.control_net = check(&key, value, errors),
+            "control_net_cpu" => config.control_net_cpu = check(&key, value, errors),
+            "threads" => config.threads = check(&key, value, errors),
+            "clip_on_cpu" => config.clip_on_cpu = check(&key, value, errors),
+            "vae_on_cpu" => config.vae_on_cpu = check(&key, value, errors),
+            "task" => config.task = check(&key, value, errors),
+            "socket_addr" => config.socket_addr = check(&key, value, errors),
+            "port" => config.port = check(&key, value, errors),
+            "download_url_prefix" => config.download_url_prefix = check(&key, value, errors),
+            "max_queue_size" => config.max_queue_size = check(&key, value, errors),
+            "presets" => config.presets = check(&key, value, errors),
+            "api_keys" => config.api_keys = check(&key, value, errors),
+            "usage_file" => config.usage_file = check(&key, value, errors),
+            "public_routes" => config.public_routes = check(&key, value, errors),
+            "api_key" => config.api_key = check(&key, value, errors),
+            "log_level" => {
+                config.log_level = check::<String>(&key, value, errors).and_then(|level| {
+                    match level.parse() {
+                        Ok(level) => Some(level),
+                        Err(_) => {
+                            errors.push(format!(
+                                "`{}`: invalid log level `{}`, expected one of trace, debug, info, warn, error and critical",
+                                key, level
+                            ));
+                            None
+                        }
+                    }
+                })
+            }
+            "defaults" => config.defaults = check(&key, value, errors),
+            "models" => config.models = parse_models(&key, value, errors),
+            _ => errors.push(format!("unknown key `{}`", key)),
+        }
+    }
+
+    check_model(&config, errors);
+
+    config
+}
+
+/// Check that the file defines at most one model, by the model settings or by the `models` list,
+/// since the backend serves a single model per process.
+fn check_model(config: &FileConfig, errors: &mut Vec<String>) {
+    let is_set = |path: &Option<String>| path.as_deref().is_some_and(|path| !path.is_empty());
+
+    if is_set(&config.model) && is_set(&config.diffusion_model) {
+        errors.push(
+            "`model` and `diffusion_model` cannot both be set: a model is either a full model or a standalone diffusion model".into(),
+        );
+    }
+
+    if config.models.len() > 1 {
+        errors.push(format!(
+            "`models`: found {} models, but the backend serves a single model per process. Run one server per model.",
+            config.models.len()
+        ));
+    }
+
+    if !config.models.is_empty() {
+        if is_set(&config.model_name) || is_set(&config.model) || is_set(&config.diffusion_model) {
+            errors.push(
+                "`models`: the model is already set by the `model_name`, `model` or `diffusion_model` settings, and the backend serves a single model per process".into(),
+            );
+        }
+        let model_settings = is_set(&config.vae)
+            || is_set(&config.clip_l)
+            || is_set(&config.t5xxl)
+            || is_set(&config.lora_model_dir)
+            || is_set(&config.control_net)
+            || config.control_net_cpu.is_some()
+            || config.threads.is_some()
+            || config.clip_on_cpu.is_some()
+            || config.vae_on_cpu.is_some()
+            || config.task.is_some();
+        if model_settings {
+            errors.push(
+                "`models`: set the model settings, e.g. `vae` or `threads`, in the model of the `models` list".into(),
+            );
+        }
+        if config.defaults.is_some() {
+            errors.push(
+                "`defaults`: set the default generation parameters in the `defaults` table of the model in the `models` list".into(),
+            );
+        }
+    }
+}
+
+fn parse_models(key: &str, value: Value, errors: &mut Vec<String>) -> Vec<ModelConfig> {
+    let list = match value {
+        Value::Array(list) => list,
+        _ => {
+            errors.push(format!("`{}`: expected a list of models", key));
+            return Vec::new();
+        }
+    };
+
+    let mut models = Vec::new();
+    for (i, value) in list.into_iter().enumerate() {
+        let prefix = format!("{}[{}]", key, i);
+
+        let map = match as_table(value, &prefix, errors) {
+            Some(map) => map,
+            None => continue,
+        };
+
+        let mut model = ModelConfig::default();
+        for (key, value) in map {
+            let path = format!("{}.{}", prefix, key);
+            match normalize_key(&key).as_str() {
+                "name" | "model_name" => set(&mut model.name, &path, value, errors),
+                "model" => set(&mut model.model, &path, value, errors),
+                "diffusion_model" => set(&mut model.diffusion_model, &path, value, errors),
+                "vae" => set(&mut model.vae, &path, value, errors),
+                "clip_l" => set(&mut model.clip_l, &path, value, errors),
+                "t5xxl" => set(&mut model.t5xxl, &path, value, errors),
+                "lora_model_dir" => model.lora_model_dir = check(&path, value, errors),
+                "control_net" => model.control_net = check(&path, value, errors),
+                "control_net_cpu" => set(&mut model.control_net_cpu, &path, value, errors),
+                "threads" => set(&mut model.threads, &path, value, errors),
+                "clip_on_cpu" => set(&mut model.clip_on_cpu, &path, value, errors),
+                "vae_on_cpu" => set(&mut model.vae_on_cpu, &path, value, errors),
+                "task" => set(&mut model.task, &path, value, errors),
+                "defaults" => set(&mut model.defaults, &path, value, errors),
+                _ => errors.push(format!("unknown key `{}`", path)),
+            }
+        }
+
+        if model.name.is_empty() {
+            errors.push(format!("`{}`: the `name` of the model is required", prefix));
+        }
+        match (model.model.is_empty(), model.diffusion_model.is_empty()) {
+            (false, false) => errors.push(format!(
+                "`{}`: `model` and `diffusion_model` cannot both be set",
+                prefix
+            )),
+            (true, true) => errors.push(format!(
+                "`{}`: either `model` or `diffusion_model` is required",
+                prefix
+            )),
+            _ => {}
+        }
+
+        models.push(model);
+    }
+
+    models
+}
+
+fn as_table(value: Value, path: &str, errors: &mut Vec<String>) -> Option<Map<String, Value>> {
+    match value {
+        Value::Object(map) => Some(map),
+        _ => {
+            match path.is_empty() {
+                true => errors.push("the config file should contain a table of settings".into()),
+                false => errors.push(format!("`{}`: expected a table of settings", path)),
+            }
+            None
+        }
+    }
+}
+
+fn normalize_key(key: &str) -> String {
+    key.replace('-', "_")
+}
+
+/// Deserialize the value of the key, recording the error if the value is invalid.
+fn check<T: DeserializeOwned>(path: &str, value: Value, errors: &mut Vec<String>) -> Option<T> {
+    match serde_json::from_value(value) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            errors.push(format!("`{}`: {}", path, e));
+            None
+        }
+    }
+}
+
+fn set<T: DeserializeOwned>(target: &mut T, path: &str, value: Value, errors: &mut Vec<String>) {
+    if let Some(value) = check(path, value, errors) {
+        *target = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(value: Value) -> (FileConfig, Vec<String>) {
+        let mut errors = Vec::new();
+        let config = parse_config(value, &mut errors);
+
+        (config, errors)
+    }
+
+    #[test]
+    fn settings_accept_dashes_and_underscores() {
+        let (config, errors) = parse(json!({
+            "model-name": "sd-v1.4",
+            "model": "sd-v1.4.gguf",
+            "max_queue_size": 4,
+            "log-level": "debug",
+            "defaults": { "steps": 25 },
+        }));
+
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(config.model_name.as_deref(), Some("sd-v1.4"));
+        assert_eq!(config.model.as_deref(), Some("sd-v1.4.gguf"));
+        assert_eq!(config.max_queue_size, Some(4));
+        assert_eq!(config.log_level, Some(LogLevel::Debug));
+        assert_eq!(
+            config.defaults.and_then(|defaults| defaults.steps),
+            Some(25)
+        );
+    }
+
+    #[test]
+    fn every_invalid_setting_is_reported() {
+        let (_, errors) = parse(json!({
+            "port": "eighty",
+            "log_level": "loud",
+            "unknown": true,
+        }));
+
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+    }
+
+    #[test]
+    fn a_single_model_list_is_accepted() {
+        let (config, errors) = parse(json!({
+            "models": [{ "name": "flux", "diffusion_model": "flux.gguf", "defaults": { "steps": 4 } }],
+        }));
+
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(config.models.len(), 1);
+        assert_eq!(config.models[0].name, "flux");
+    }
+
+    #[test]
+    fn several_models_are_rejected() {
+        let (_, errors) = parse(json!({
+            "models": [
+                { "name": "sd", "model": "sd.gguf" },
+                { "name": "flux", "diffusion_model": "flux.gguf" },
+            ],
+        }));
+
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].contains("single model"));
+    }
+
+    #[test]
+    fn a_model_list_with_model_settings_is_rejected() {
+        let (_, errors) = parse(json!({
+            "model": "sd.gguf",
+            "models": [{ "name": "flux", "diffusion_model": "flux.gguf" }],
+        }));
+
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+    }
+
+    #[test]
+    fn conflicting_or_missing_model_paths_are_rejected() {
+        let (_, errors) = parse(json!({
+            "model": "sd.gguf",
+            "diffusion_model": "flux.gguf",
+        }));
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+
+        let (_, errors) = parse(json!({
+            "models": [{ "name": "sd", "model": "sd.gguf", "diffusion_model": "flux.gguf" }],
+        }));
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+
+        let (_, errors) = parse(json!({ "models": [{ "name": "sd" }] }));
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].contains("is required"));
+    }
+}
@@ -1,111 +1,198 @@
-use hyper::{Body, Response};
+use hyper::{
+    header::{
+        HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+        ACCESS_CONTROL_ALLOW_ORIGIN, CONTENT_TYPE, WWW_AUTHENTICATE,
+    },
+    Body, Response, StatusCode,
+};
 use thiserror::Error;
 
 #[allow(dead_code)]
 pub(crate) fn not_implemented() -> Response<Body> {
-    // log error
-    error!(target: "stdout", "501 Not Implemented");
-
-    Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .status(hyper::StatusCode::NOT_IMPLEMENTED)
-        .body(Body::from("501 Not Implemented"))
-        .unwrap()
+    error_response(StatusCode::NOT_IMPLEMENTED, "server_error", "", None, None)
 }
 
 pub(crate) fn internal_server_error(msg: impl AsRef<str>) -> Response<Body> {
-    let err_msg = match msg.as_ref().is_empty() {
-        true => "500 Internal Server Error".to_string(),
-        false => format!("500 Internal Server Error: {}", msg.as_ref()),
-    };
-
-    // log error
-    error!(target: "stdout", "{}", &err_msg);
-
-    Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-        .body(Body::from(err_msg))
-        .unwrap()
+    error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "server_error",
+        msg,
+        None,
+        None,
+    )
 }
 
 pub(crate) fn method_not_allowed(msg: impl AsRef<str>) -> Response<Body> {
-    let err_msg = match msg.as_ref().is_empty() {
-        true => "405 Method Not Allowed".to_string(),
-        false => format!("405 Method Not Allowed: {}", msg.as_ref()),
-    };
-
-    // log error
-    error!(target: "stdout", "{}", &err_msg);
-
-    Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .status(hyper::StatusCode::METHOD_NOT_ALLOWED)
-        .body(Body::from(err_msg))
-        .unwrap()
+    error_response(
+        StatusCode::METHOD_NOT_ALLOWED,
+        "invalid_request_error",
+        msg,
+        None,
+        Some("method_not_allowed"),
+    )
 }
 
 pub(crate) fn bad_request(msg: impl AsRef<str>) -> Response<Body> {
-    let err_msg = match msg.as_ref().is_empty() {
-        true => "400 Bad Request".to_string(),
-        false => format!("400 Bad Request: {}", msg.as_ref()),
-    };
+    error_response(
+        StatusCode::BAD_REQUEST,
+        "invalid_request_error",
+        msg,
+        None,
+        None,
+    )
+}
 
-    // log error
-    error!(target: "stdout", "{}", &err_msg);
+pub(crate) fn unauthorized(msg: impl AsRef<str>) -> Response<Body> {
+    let mut response = error_response(
+        StatusCode::UNAUTHORIZED,
+        "authentication_error",
+        msg,
+        None,
+        Some("invalid_api_key"),
+    );
+    response
+        .headers_mut()
+        .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
 
-    Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .status(hyper::StatusCode::BAD_REQUEST)
-        .body(Body::from(err_msg))
-        .unwrap()
+    response
 }
 
-pub(crate) fn unauthorized(msg: impl AsRef<str>) -> Response<Body> {
-    let err_msg = match msg.as_ref().is_empty() {
-        true => "401 Unauthorized".to_string(),
-        false => format!("401 Unauthorized: {}", msg.as_ref()),
-    };
+pub(crate) fn forbidden(msg: impl AsRef<str>) -> Response<Body> {
+    error_response(
+        StatusCode::FORBIDDEN,
+        "permission_error",
+        msg,
+        None,
+        Some("insufficient_scope"),
+    )
+}
 
-    // log error
-    error!(target: "stdout", "{}", &err_msg);
+pub(crate) fn conflict(msg: impl AsRef<str>) -> Response<Body> {
+    error_response(
+        StatusCode::CONFLICT,
+        "invalid_request_error",
+        msg,
+        None,
+        Some("conflict"),
+    )
+}
+
+pub(crate) fn too_many_requests(msg: impl AsRef<str>) -> Response<Body> {
+    error_response(
+        StatusCode::TOO_MANY_REQUESTS,
+        "rate_limit_error",
+        msg,
+        None,
+        Some("rate_limit_exceeded"),
+    )
+}
 
-    Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .status(hyper::StatusCode::UNAUTHORIZED)
-        .body(Body::from(err_msg))
-        .unwrap()
+pub(crate) fn service_unavailable(msg: impl AsRef<str>) -> Response<Body> {
+    error_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "server_error",
+        msg,
+        None,
+        Some("service_unavailable"),
+    )
+}
+
+pub(crate) fn not_found(msg: impl AsRef<str>) -> Response<Body> {
+    error_response(
+        StatusCode::NOT_FOUND,
+        "invalid_request_error",
+        msg,
+        None,
+        Some("not_found"),
+    )
 }
 
 pub(crate) fn invalid_endpoint(msg: impl AsRef<str>) -> Response<Body> {
     let err_msg = match msg.as_ref().is_empty() {
-        true => "404 The requested service endpoint is not found".to_string(),
+        true => "The requested service endpoint is not found".to_string(),
         false => format!(
-            "404 The requested service endpoint is not found: {}",
+            "The requested service endpoint is not found: {}",
             msg.as_ref()
         ),
     };
 
+    error_response(
+        StatusCode::NOT_FOUND,
+        "invalid_request_error",
+        err_msg,
+        None,
+        Some("unknown_url"),
+    )
+}
+
+pub(crate) fn unsupported_media_type(msg: impl AsRef<str>) -> Response<Body> {
+    error_response(
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "invalid_request_error",
+        msg,
+        None,
+        Some("unsupported_media_type"),
+    )
+}
+
+/// Report a failure of the backend while running the inference.
+pub(crate) fn backend_error(msg: impl AsRef<str>) -> Response<Body> {
+    error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "server_error",
+        msg,
+        None,
+        Some("backend_error"),
+    )
+}
+
+/// Build an error response with the JSON body of the OpenAI API:
+/// `{"error": {"message", "type", "param", "code"}}`. An empty message is replaced by the reason
+/// phrase of the status code.
+pub(crate) fn error_response(
+    status: StatusCode,
+    error_type: &str,
+    msg: impl AsRef<str>,
+    param: Option<&str>,
+    code: Option<&str>,
+) -> Response<Body> {
+    let message = match msg.as_ref().is_empty() {
+        true => status.canonical_reason().unwrap_or_default().to_string(),
+        false => msg.as_ref().to_string(),
+    };
+
     // log error
-    error!(target: "stdout", "{}", &err_msg);
+    error!(target: "stdout", "{}: {}", status, &message);
+
+    let body = error_body(error_type, message, param, code);
+
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+
+    let headers = response.headers_mut();
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+    headers.insert(ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static("*"));
+    headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static("*"));
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
 
-    Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "*")
-        .header("Access-Control-Allow-Headers", "*")
-        .status(hyper::StatusCode::NOT_FOUND)
-        .body(Body::from(err_msg))
-        .unwrap()
+    response
+}
+
+/// Build the JSON body of an error: `{"error": {"message", "type", "param", "code"}}`.
+pub(crate) fn error_body(
+    error_type: &str,
+    msg: impl AsRef<str>,
+    param: Option<&str>,
+    code: Option<&str>,
+) -> serde_json::Value {
+    serde_json::json!({
+        "error": {
+            "message": msg.as_ref(),
+            "type": error_type,
+            "param": param,
+            "code": code,
+        }
+    })
 }
 
 #[derive(Error, Clone, Debug, PartialEq, Eq)]
@@ -116,4 +203,58 @@ pub enum ServerError {
     /// Generic error returned while performing an operation
     #[error("{0}")]
     Operation(String),
+    /// Error returned while the request is invalid. `param` names the offending field
+    #[error("{message}")]
+    InvalidRequest {
+        message: String,
+        param: Option<String>,
+        code: Option<String>,
+    },
+    /// Error returned while the requested resource does not exist
+    #[error("{message}")]
+    NotFound {
+        message: String,
+        param: Option<String>,
+        code: Option<String>,
+    },
+    /// Error returned while the content type of the request body is not supported
+    #[error("{0}")]
+    UnsupportedMediaType(String),
+    /// Error returned while the server cannot handle the request for now
+    #[error("{0}")]
+    Unavailable(String),
+}
+impl ServerError {
+    /// Build the error response.
+    pub(crate) fn into_response(self) -> Response<Body> {
+        match self {
+            ServerError::ArgumentError(msg) | ServerError::Operation(msg) => {
+                internal_server_error(msg)
+            }
+            ServerError::InvalidRequest {
+                message,
+                param,
+                code,
+            } => error_response(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                message,
+                param.as_deref(),
+                code.as_deref(),
+            ),
+            ServerError::NotFound {
+                message,
+                param,
+                code,
+            } => error_response(
+                StatusCode::NOT_FOUND,
+                "invalid_request_error",
+                message,
+                param.as_deref(),
+                code.as_deref(),
+            ),
+            ServerError::UnsupportedMediaType(msg) => unsupported_media_type(msg),
+            ServerError::Unavailable(msg) => service_unavailable(msg),
+        }
+    }
 }
@@ -0,0 +1,327 @@
+use crate::{
+    queue,
+    utils::{gen_job_id, unix_timestamp},
+};
+use endpoints::images::ListImagesResponse;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+use tokio::sync::Notify;
+
+/// Number of seconds a finished job is kept before it is pruned.
+const JOB_RETENTION_SECS: u64 = 60 * 60;
+
+// registry of the image generation jobs
+static JOBS: Lazy<Mutex<HashMap<String, Job>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Status of an image generation job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum JobStatus {
+    /// The job is waiting to be run.
+    Queued,
+    /// The job is being run.
+    Running,
+    /// The job finished and the images are available.
+    Succeeded,
+    /// The job finished with an error.
+    Failed,
+    /// The job was cancelled while queued.
+    Cancelled,
+}
+
+/// An image generation job running in the background.
+#[derive(Debug, Serialize)]
+pub(crate) struct Job {
+    /// The job identifier, which can be referenced in the API endpoints.
+    pub(crate) id: String,
+    /// The object type, which is always `image.generation.job`.
+    pub(crate) object: String,
+    /// The status of the job.
+    pub(crate) status: JobStatus,
+    /// The Unix timestamp (in seconds) for when the job was created.
+    pub(crate) created_at: u64,
+    /// The 1-based position of the job in the generation queue, available while the job is queued.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) queue_position: Option<usize>,
+    /// Estimated seconds before the job starts running, available while the job is queued.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) estimated_wait: Option<u64>,
+    /// The Unix timestamp (in seconds) for when the job started running.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) started_at: Option<u64>,
+    /// The Unix timestamp (in seconds) for when the job finished.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) finished_at: Option<u64>,
+    /// The generated images, available once the job succeeded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) result: Option<ListImagesResponse>,
+    /// The error message, available once the job failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    /// The name of the API key that created the job, if any.
+    #[serde(skip)]
+    key: Option<String>,
+    /// The ticket of the job in the generation queue.
+    #[serde(skip)]
+    queue_ticket: u64,
+    /// Signal waking up a queued job once it is cancelled.
+    #[serde(skip)]
+    cancelled: Arc<Notify>,
+}
+impl Job {
+    /// Whether the job is visible to the given key. `None` sees every job.
+    fn is_visible_to(&self, key: Option<&str>) -> bool {
+        match key {
+            Some(key) => self.key.as_deref() == Some(key),
+            None => true,
+        }
+    }
+
+    /// Refresh the queue position of a queued job.
+    fn refresh_queue_position(&mut self) {
+        if self.status != JobStatus::Queued {
+            return;
+        }
+
+        match queue::position(self.queue_ticket) {
+            Some((position, estimated_wait)) => {
+                self.queue_position = Some(position);
+                self.estimated_wait = estimated_wait;
+            }
+            None => {
+                self.queue_position = None;
+                self.estimated_wait = None;
+            }
+        }
+    }
+}
+
+fn jobs() -> MutexGuard<'static, HashMap<String, Job>> {
+    match JOBS.lock() {
+        Ok(jobs) => jobs,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// The handle of a job, used by the task running it.
+#[derive(Debug)]
+pub(crate) struct JobHandle {
+    /// The job identifier.
+    pub(crate) id: String,
+    /// Signal notified once the job is cancelled while queued.
+    pub(crate) cancelled: Arc<Notify>,
+}
+
+/// Error returned if a job cannot be cancelled.
+#[derive(Debug)]
+pub(crate) enum CancelError {
+    /// The job does not exist.
+    NotFound,
+    /// The job is being run, and the backend cannot be interrupted.
+    Running,
+    /// The job already finished with the given status.
+    Finished(JobStatus),
+}
+
+/// Register a new job of the given API key, waiting with the given queue ticket.
+pub(crate) fn create_job(queue_ticket: u64, key: Option<String>) -> JobHandle {
+    let now = unix_timestamp();
+    let id = gen_job_id();
+    let cancelled = Arc::new(Notify::new());
+
+    let mut jobs = jobs();
+
+    // drop the jobs finished long ago
+    jobs.retain(|_, job| match job.finished_at {
+        Some(finished_at) => now.saturating_sub(finished_at) < JOB_RETENTION_SECS,
+        None => true,
+    });
+
+    jobs.insert(
+        id.clone(),
+        Job {
+            id: id.clone(),
+            object: "image.generation.job".to_string(),
+            status: JobStatus::Queued,
+            created_at: now,
+            queue_position: None,
+            estimated_wait: None,
+            started_at: None,
+            finished_at: None,
+            result: None,
+            error: None,
+            key,
+            queue_ticket,
+            cancelled: cancelled.clone(),
+        },
+    );
+
+    JobHandle { id, cancelled }
+}
+
+/// Mark the job as running. Returns `false` if the job is not queued anymore.
+pub(crate) fn mark_running(id: &str) -> bool {
+    match jobs().get_mut(id) {
+        Some(job) if job.status == JobStatus::Queued => {
+            job.status = JobStatus::Running;
+            job.started_at = Some(unix_timestamp());
+            job.queue_position = None;
+            job.estimated_wait = None;
+
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Cancel the job, which is removed from the generation queue at once.
+///
+/// Only a queued job can be cancelled: the backend cannot be interrupted, and it holds the
+/// runtime while it runs, so a running job finishes anyway. The jobs of other keys than the
+/// given key, if any, are not found.
+pub(crate) fn cancel_job(id: &str, key: Option<&str>) -> Result<JobStatus, CancelError> {
+    let mut jobs = jobs();
+
+    let job = match jobs.get_mut(id) {
+        Some(job) if job.is_visible_to(key) => job,
+        _ => return Err(CancelError::NotFound),
+    };
+
+    match job.status {
+        JobStatus::Queued => {
+            job.status = JobStatus::Cancelled;
+            job.finished_at = Some(unix_timestamp());
+            job.queue_position = None;
+            job.estimated_wait = None;
+
+            // wake up the task waiting in the queue
+            job.cancelled.notify_one();
+        }
+        JobStatus::Running => return Err(CancelError::Running),
+        status => return Err(CancelError::Finished(status)),
+    }
+
+    Ok(job.status)
+}
+
+/// Mark the job as succeeded and store the generated images.
+pub(crate) fn mark_succeeded(id: &str, result: ListImagesResponse) {
+    if let Some(job) = jobs().get_mut(id) {
+        job.status = JobStatus::Succeeded;
+        job.finished_at = Some(unix_timestamp());
+        job.result = Some(result);
+    }
+}
+
+/// Mark the job as failed and store the error message.
+pub(crate) fn mark_failed(id: &str, err_msg: impl Into<String>) {
+    if let Some(job) = jobs().get_mut(id) {
+        job.status = JobStatus::Failed;
+        job.finished_at = Some(unix_timestamp());
+        job.error = Some(err_msg.into());
+    }
+}
+
+/// Serialize the job with the given id. Returns `None` if the job does not exist, or belongs to
+/// another key than the given key, if any.
+pub(crate) fn job_to_json(
+    id: &str,
+    key: Option<&str>,
+) -> Option<Result<String, serde_json::Error>> {
+    jobs()
+        .get_mut(id)
+        .filter(|job| job.is_visible_to(key))
+        .map(|job| {
+            job.refresh_queue_position();
+            serde_json::to_string(job)
+        })
+}
+
+/// Serialize the jobs of the given key, or all jobs without a key, sorted by creation time.
+pub(crate) fn list_jobs_to_json(key: Option<&str>) -> Result<String, serde_json::Error> {
+    let mut jobs = jobs();
+
+    jobs.values_mut().for_each(Job::refresh_queue_position);
+
+    let mut data: Vec<&Job> = jobs.values().filter(|job| job.is_visible_to(key)).collect();
+    data.sort_by_key(|job| job.created_at);
+
+    serde_json::to_string(&serde_json::json!({
+        "object": "list",
+        "data": data,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A queue ticket that is never in the generation queue.
+    const NO_TICKET: u64 = u64::MAX;
+
+    #[test]
+    fn queued_jobs_are_cancelled_at_once() {
+        let job = create_job(NO_TICKET, None);
+
+        assert!(matches!(
+            cancel_job(&job.id, None),
+            Ok(JobStatus::Cancelled)
+        ));
+        assert!(!mark_running(&job.id));
+        assert!(matches!(
+            cancel_job(&job.id, None),
+            Err(CancelError::Finished(JobStatus::Cancelled))
+        ));
+    }
+
+    #[test]
+    fn running_and_finished_jobs_cannot_be_cancelled() {
+        let job = create_job(NO_TICKET, None);
+
+        assert!(mark_running(&job.id));
+        assert!(!mark_running(&job.id));
+        assert!(matches!(
+            cancel_job(&job.id, None),
+            Err(CancelError::Running)
+        ));
+
+        mark_failed(&job.id, "backend error");
+        assert!(matches!(
+            cancel_job(&job.id, None),
+            Err(CancelError::Finished(JobStatus::Failed))
+        ));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&job_to_json(&job.id, None).unwrap().unwrap()).unwrap();
+        assert_eq!(json["status"], "failed");
+        assert_eq!(json["error"], "backend error");
+    }
+
+    #[test]
+    fn jobs_are_only_visible_to_their_key() {
+        let job = create_job(NO_TICKET, Some("jobs-test-owner".to_string()));
+
+        assert!(job_to_json(&job.id, Some("jobs-test-owner")).is_some());
+        assert!(job_to_json(&job.id, None).is_some());
+        assert!(job_to_json(&job.id, Some("jobs-test-other")).is_none());
+        assert!(!list_jobs_to_json(Some("jobs-test-other"))
+            .unwrap()
+            .contains(&job.id));
+        assert!(list_jobs_to_json(Some("jobs-test-owner"))
+            .unwrap()
+            .contains(&job.id));
+
+        assert!(matches!(
+            cancel_job(&job.id, Some("jobs-test-other")),
+            Err(CancelError::NotFound)
+        ));
+        assert!(matches!(
+            cancel_job(&job.id, Some("jobs-test-owner")),
+            Ok(JobStatus::Cancelled)
+        ));
+    }
+}
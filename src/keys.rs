@@ -0,0 +1,592 @@
+use crate::{
+    error::{self, ServerError},
+    limits::RateLimits,
+};
+use hyper::{header::AUTHORIZATION, Body, Method, Request, Response};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+/// Name of the key set by the `API_KEY` environment variable or the `api_key` setting.
+const DEFAULT_KEY_NAME: &str = "default";
+
+/// Route of the health check, always served without an API key.
+pub(crate) const HEALTH_ROUTE: &str = "/health";
+
+// the API keys accepted by the server
+static KEYS: Lazy<Mutex<KeyStore>> = Lazy::new(|| Mutex::new(KeyStore::default()));
+
+/// A permission granted to an API key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) enum Scope {
+    /// Generate images, and retrieve or cancel the generation jobs.
+    #[serde(rename = "images:generate")]
+    ImagesGenerate,
+    /// Edit images and create image variations.
+    #[serde(rename = "images:edit")]
+    ImagesEdit,
+    /// List, retrieve and download files.
+    #[serde(rename = "files:read")]
+    FilesRead,
+    /// Upload files.
+    #[serde(rename = "files:write")]
+    FilesWrite,
+    /// Delete files.
+    #[serde(rename = "files:delete")]
+    FilesDelete,
+    /// Read the Prometheus metrics.
+    #[serde(rename = "metrics:read")]
+    MetricsRead,
+    /// Manage the presets, and read the usage of every key. Grants every other scope.
+    #[serde(rename = "admin")]
+    Admin,
+}
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scope = match self {
+            Scope::ImagesGenerate => "images:generate",
+            Scope::ImagesEdit => "images:edit",
+            Scope::FilesRead => "files:read",
+            Scope::FilesWrite => "files:write",
+            Scope::FilesDelete => "files:delete",
+            Scope::MetricsRead => "metrics:read",
+            Scope::Admin => "admin",
+        };
+
+        f.write_str(scope)
+    }
+}
+
+/// An API key defined in the key file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ApiKey {
+    /// The name of the key, e.g. the team or the partner it is handed to.
+    name: String,
+    /// The secret sent in the `Authorization: Bearer` header.
+    key: String,
+    /// The permissions of the key.
+    #[serde(default)]
+    scopes: Vec<Scope>,
+    /// Whether the key is revoked.
+    #[serde(default)]
+    revoked: bool,
+    /// Maximum number of image requests in any minute.
+    #[serde(default)]
+    requests_per_minute: Option<u64>,
+    /// Maximum cost of the image requests in any hour.
+    #[serde(default)]
+    cost_per_hour: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyFile {
+    #[serde(default)]
+    keys: Vec<ApiKey>,
+}
+
+/// The key a request is authenticated with, stored in the request extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct KeyInfo {
+    /// The name of the key.
+    pub(crate) name: String,
+    /// The permissions of the key.
+    pub(crate) scopes: Vec<Scope>,
+    /// The rate limits of the key.
+    pub(crate) limits: RateLimits,
+}
+impl KeyInfo {
+    /// Whether the key grants the scope.
+    pub(crate) fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&Scope::Admin)
+    }
+}
+
+#[derive(Debug, Default)]
+struct KeyStore {
+    /// The key file, reloaded when its modification time changes.
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    keys: Vec<ApiKey>,
+    /// The key set by the `API_KEY` environment variable or the `api_key` setting.
+    default_key: Option<ApiKey>,
+    /// The paths served without an API key for `GET` requests, set by the `--public-routes`
+    /// option. A path ending with `/` matches every path under it.
+    public_routes: Vec<String>,
+}
+impl KeyStore {
+    /// Reload the key file if it changed since it was read. A file that fails to load leaves the
+    /// keys unchanged.
+    fn refresh(&mut self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let modified = fs::metadata(path).and_then(|metadata| metadata.modified());
+        let modified = match modified {
+            Ok(modified) => modified,
+            Err(e) => {
+                // log
+                warn!(target: "stdout", "Failed to read the key file {}. The current keys are kept. {}", path.display(), e);
+
+                return;
+            }
+        };
+        if self.modified == Some(modified) {
+            return;
+        }
+
+        match load_keys(path) {
+            Ok(keys) => {
+                // log
+                info!(target: "stdout", "Loaded {} API keys from {}", keys.len(), path.display());
+
+                self.keys = keys;
+            }
+            Err(e) => {
+                // log
+                error!(target: "stdout", "{} The current keys are kept.", e);
+            }
+        }
+        self.modified = Some(modified);
+    }
+}
+
+fn keys() -> MutexGuard<'static, KeyStore> {
+    match KEYS.lock() {
+        Ok(keys) => keys,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Set up the API keys: the keys of the key file, if any, and the key set by the `API_KEY`
+/// environment variable or the `api_key` setting, if any, which is granted every scope. The
+/// public routes are served without a key.
+pub(crate) fn init_keys(
+    key_file: Option<PathBuf>,
+    default_key: Option<String>,
+    public_routes: Vec<String>,
+) -> Result<(), ServerError> {
+    let mut store = keys();
+
+    if let Some(route) = public_routes.iter().find(|route| !route.starts_with('/')) {
+        return Err(ServerError::ArgumentError(format!(
+            "Invalid public route `{}`. A route should be a path starting with `/`.",
+            route
+        )));
+    }
+    store.public_routes = public_routes;
+
+    if let Some(key) = default_key {
+        if key.is_empty() {
+            return Err(ServerError::ArgumentError(
+                "The API key should not be empty.".into(),
+            ));
+        }
+
+        store.default_key = Some(ApiKey {
+            name: DEFAULT_KEY_NAME.to_string(),
+            key,
+            scopes: vec![Scope::Admin],
+            revoked: false,
+            requests_per_minute: None,
+            cost_per_hour: None,
+        });
+    }
+
+    if let Some(path) = key_file {
+        let keys = load_keys(&path)?;
+
+        // log
+        info!(target: "stdout", "Loaded {} API keys from {}", keys.len(), path.display());
+
+        store.modified = fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        store.keys = keys;
+        store.path = Some(path);
+    }
+
+    Ok(())
+}
+
+/// Read the TOML, YAML or JSON key file, chosen by the file extension.
+fn load_keys(path: &Path) -> Result<Vec<ApiKey>, ServerError> {
+    let text = fs::read_to_string(path).map_err(|e| {
+        ServerError::ArgumentError(format!(
+            "Failed to read the key file {}. {}",
+            path.display(),
+            e
+        ))
+    })?;
+
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase());
+    let key_file: Result<KeyFile, String> = match extension.as_deref() {
+        Some("toml") => toml::from_str(&text).map_err(|e| e.to_string()),
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&text).map_err(|e| e.to_string()),
+        Some("json") => serde_json::from_str(&text).map_err(|e| e.to_string()),
+        _ => {
+            return Err(ServerError::ArgumentError(format!(
+                "Unsupported key file {}. The file extension should be `.toml`, `.yaml`, `.yml` or `.json`.",
+                path.display()
+            )));
+        }
+    };
+    let key_file = key_file.map_err(|e| {
+        ServerError::ArgumentError(format!(
+            "Failed to parse the key file {}. {}",
+            path.display(),
+            e
+        ))
+    })?;
+
+    let mut names = HashSet::new();
+    let mut secrets = HashSet::new();
+    for api_key in key_file.keys.iter() {
+        if api_key.name.is_empty() || api_key.key.is_empty() {
+            return Err(ServerError::ArgumentError(format!(
+                "Invalid key file {}. Every key should have a `name` and a `key`.",
+                path.display()
+            )));
+        }
+        if api_key.requests_per_minute == Some(0) || api_key.cost_per_hour == Some(0) {
+            return Err(ServerError::ArgumentError(format!(
+                "Invalid key file {}. The limits of the key `{}` should be positive. Revoke the key to reject its requests.",
+                path.display(),
+                api_key.name
+            )));
+        }
+        if !names.insert(api_key.name.as_str()) {
+            return Err(ServerError::ArgumentError(format!(
+                "Invalid key file {}. The key name `{}` is used more than once.",
+                path.display(),
+                api_key.name
+            )));
+        }
+        if !secrets.insert(api_key.key.as_str()) {
+            return Err(ServerError::ArgumentError(format!(
+                "Invalid key file {}. The key `{}` has the same secret as another key.",
+                path.display(),
+                api_key.name
+            )));
+        }
+    }
+
+    Ok(key_file.keys)
+}
+
+/// Whether any API key is configured. Without keys, the server accepts every request.
+pub(crate) fn is_enabled() -> bool {
+    let store = keys();
+
+    store.default_key.is_some() || store.path.is_some()
+}
+
+/// Find the key with the given secret. Revoked keys are not accepted.
+///
+/// The secret is compared with every key in constant time, so the response time does not tell how
+/// much of a key matched.
+pub(crate) fn authenticate(secret: &str) -> Option<KeyInfo> {
+    let mut store = keys();
+    store.refresh();
+
+    let digest = Sha256::digest(secret.as_bytes());
+
+    let mut found = None;
+    for api_key in store.default_key.iter().chain(store.keys.iter()) {
+        let matched = constant_time_eq(&digest, &Sha256::digest(api_key.key.as_bytes()));
+        if matched && !api_key.revoked && found.is_none() {
+            found = Some(KeyInfo {
+                name: api_key.name.clone(),
+                scopes: api_key.scopes.clone(),
+                limits: RateLimits {
+                    requests_per_minute: api_key.requests_per_minute,
+                    cost_per_hour: api_key.cost_per_hour,
+                },
+            });
+        }
+    }
+
+    found
+}
+
+/// Compare the bytes without exiting at the first difference.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Return the scope needed for the request, or `None` if any valid key may send it.
+pub(crate) fn required_scope(method: &Method, path: &str) -> Option<Scope> {
+    let path = path.trim_end_matches('/');
+
+    if path == "/metrics" {
+        Some(Scope::MetricsRead)
+    } else if path == "/v1/images/generations" || path.starts_with("/v1/jobs") {
+        Some(Scope::ImagesGenerate)
+    } else if path == "/v1/images/edits" || path == "/v1/images/variations" {
+        Some(Scope::ImagesEdit)
+    } else if path.starts_with("/v1/files") {
+        match *method {
+            Method::POST => Some(Scope::FilesWrite),
+            Method::DELETE => Some(Scope::FilesDelete),
+            _ => Some(Scope::FilesRead),
+        }
+    } else if path.starts_with("/v1/presets") {
+        match *method {
+            Method::PUT | Method::DELETE => Some(Scope::Admin),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+/// Whether the request is served without an API key: the CORS preflight requests, the
+/// [`HEALTH_ROUTE`] and the `GET` requests of the public routes.
+pub(crate) fn is_public(method: &Method, path: &str) -> bool {
+    if method == Method::OPTIONS {
+        return true;
+    }
+    if method != Method::GET {
+        return false;
+    }
+
+    path.trim_end_matches('/') == HEALTH_ROUTE
+        || keys()
+            .public_routes
+            .iter()
+            .any(|route| matches_route(route, path))
+}
+
+/// Whether the path is the route, or is under the route if the route ends with `/`.
+fn matches_route(route: &str, path: &str) -> bool {
+    match route.ends_with('/') {
+        true => path.starts_with(route),
+        false => path.trim_end_matches('/') == route.trim_end_matches('/'),
+    }
+}
+
+/// Authenticate the request with the `Authorization: Bearer <key>` header, check the scope of the
+/// key, and store the [`KeyInfo`] in the request extensions.
+///
+/// Returns the error response if the request is rejected. Without keys, and for the public
+/// routes, every request is accepted. The key itself is never logged.
+pub(crate) fn authorize(req: &mut Request<Body>) -> Option<Response<Body>> {
+    if !is_enabled() || is_public(req.method(), req.uri().path()) {
+        return None;
+    }
+
+    let secret = match req.headers().get(AUTHORIZATION) {
+        Some(value) => match value.to_str().ok().and_then(bearer_token) {
+            Some(secret) => secret,
+            None => {
+                let err_msg =
+                    "Malformed `Authorization` header. The header should be `Bearer <API key>`.";
+                return Some(error::unauthorized(err_msg));
+            }
+        },
+        None => {
+            let err_msg =
+                "Missing API key. Send it in the `Authorization: Bearer <API key>` header.";
+            return Some(error::unauthorized(err_msg));
+        }
+    };
+
+    let key_info = match authenticate(secret) {
+        Some(key_info) => key_info,
+        None => {
+            let err_msg = "Invalid API key.";
+            return Some(error::unauthorized(err_msg));
+        }
+    };
+
+    if let Some(scope) = required_scope(req.method(), req.uri().path()) {
+        if !key_info.allows(scope) {
+            let err_msg = format!(
+                "The API key `{}` does not have the `{}` scope.",
+                key_info.name, scope
+            );
+            return Some(error::forbidden(err_msg));
+        }
+    }
+
+    // log
+    info!(target: "stdout", "API key: {}", key_info.name);
+
+    req.extensions_mut().insert(key_info);
+
+    None
+}
+
+/// Return the token of the `Bearer <token>` header value, or `None` if the value is malformed.
+fn bearer_token(value: &str) -> Option<&str> {
+    let (scheme, token) = value.trim().split_once(' ')?;
+    let token = token.trim();
+
+    match scheme.eq_ignore_ascii_case("bearer") && !token.is_empty() && !token.contains(' ') {
+        true => Some(token),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bearer_tokens_are_parsed() {
+        assert_eq!(bearer_token("Bearer sk-test"), Some("sk-test"));
+        assert_eq!(bearer_token("bearer  sk-test "), Some("sk-test"));
+        assert_eq!(bearer_token("BEARER sk-test"), Some("sk-test"));
+
+        assert_eq!(bearer_token("sk-test"), None);
+        assert_eq!(bearer_token("Bearer"), None);
+        assert_eq!(bearer_token("Bearer "), None);
+        assert_eq!(bearer_token("Basic sk-test"), None);
+        assert_eq!(bearer_token("Bearer sk test"), None);
+    }
+
+    #[test]
+    fn routes_require_their_scope() {
+        let cases = [
+            (Method::GET, "/metrics", Some(Scope::MetricsRead)),
+            (
+                Method::POST,
+                "/v1/images/generations",
+                Some(Scope::ImagesGenerate),
+            ),
+            (Method::GET, "/v1/jobs/job_1", Some(Scope::ImagesGenerate)),
+            (Method::POST, "/v1/images/edits", Some(Scope::ImagesEdit)),
+            (
+                Method::POST,
+                "/v1/images/variations/",
+                Some(Scope::ImagesEdit),
+            ),
+            (Method::GET, "/v1/files", Some(Scope::FilesRead)),
+            (
+                Method::GET,
+                "/v1/files/download/file_1",
+                Some(Scope::FilesRead),
+            ),
+            (Method::POST, "/v1/files", Some(Scope::FilesWrite)),
+            (Method::DELETE, "/v1/files/file_1", Some(Scope::FilesDelete)),
+            (Method::PUT, "/v1/presets/fast", Some(Scope::Admin)),
+            (Method::GET, "/v1/presets", None),
+            (Method::GET, "/v1/models", None),
+            (Method::GET, "/v1/usage", None),
+        ];
+
+        for (method, path, scope) in cases {
+            assert_eq!(required_scope(&method, path), scope, "{} {}", method, path);
+        }
+    }
+
+    #[test]
+    fn admin_grants_every_scope() {
+        let key_info = |scopes| KeyInfo {
+            name: "test".to_string(),
+            scopes,
+            limits: RateLimits::default(),
+        };
+
+        assert!(key_info(vec![Scope::Admin]).allows(Scope::MetricsRead));
+        assert!(key_info(vec![Scope::FilesRead]).allows(Scope::FilesRead));
+        assert!(!key_info(vec![Scope::FilesRead]).allows(Scope::FilesWrite));
+        assert!(!key_info(vec![Scope::MetricsRead]).allows(Scope::Admin));
+    }
+
+    #[test]
+    fn only_the_health_check_and_the_preflight_requests_are_public_by_default() {
+        assert!(is_public(&Method::GET, "/health"));
+        assert!(is_public(&Method::OPTIONS, "/v1/images/generations"));
+
+        assert!(!is_public(&Method::POST, "/health"));
+        assert!(!is_public(&Method::GET, "/echo"));
+        assert!(!is_public(
+            &Method::GET,
+            "/v1/files/download/file_1/output.png"
+        ));
+    }
+
+    #[test]
+    fn public_routes_match_their_paths() {
+        assert!(matches_route("/echo", "/echo"));
+        assert!(matches_route("/echo", "/echo/"));
+        assert!(!matches_route("/echo", "/echo/more"));
+
+        assert!(matches_route(
+            "/v1/files/download/",
+            "/v1/files/download/file_1"
+        ));
+        assert!(!matches_route("/v1/files/download/", "/v1/files"));
+    }
+
+    #[test]
+    fn secrets_are_compared_byte_by_byte() {
+        assert!(constant_time_eq(b"sk-test", b"sk-test"));
+        assert!(!constant_time_eq(b"sk-test", b"sk-tesT"));
+        assert!(!constant_time_eq(b"sk-test", b"sk-test2"));
+        assert!(!constant_time_eq(b"", b"sk-test"));
+    }
+
+    #[test]
+    fn invalid_key_files_are_rejected() {
+        let dir = std::env::temp_dir().join(format!("sd-api-server-keys-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let cases = [
+            (
+                "valid.json",
+                r#"{"keys": [{"name": "a", "key": "sk-a", "scopes": ["images:generate"]}]}"#,
+                true,
+            ),
+            (
+                "unknown_scope.json",
+                r#"{"keys": [{"name": "a", "key": "sk-a", "scopes": ["images:delete"]}]}"#,
+                false,
+            ),
+            (
+                "empty_key.json",
+                r#"{"keys": [{"name": "a", "key": ""}]}"#,
+                false,
+            ),
+            (
+                "zero_limit.json",
+                r#"{"keys": [{"name": "a", "key": "sk-a", "cost_per_hour": 0}]}"#,
+                false,
+            ),
+            (
+                "same_name.json",
+                r#"{"keys": [{"name": "a", "key": "sk-a"}, {"name": "a", "key": "sk-b"}]}"#,
+                false,
+            ),
+            (
+                "same_secret.json",
+                r#"{"keys": [{"name": "a", "key": "sk-a"}, {"name": "b", "key": "sk-a"}]}"#,
+                false,
+            ),
+            ("keys.ini", r#"{"keys": []}"#, false),
+        ];
+
+        for (file_name, text, valid) in cases {
+            let path = dir.join(file_name);
+            fs::write(&path, text).unwrap();
+
+            assert_eq!(load_keys(&path).is_ok(), valid, "{}", file_name);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
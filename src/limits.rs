@@ -0,0 +1,467 @@
+use crate::{
+    error::{self, ServerError},
+    keys::KeyInfo,
+};
+use hyper::{
+    header::{HeaderName, HeaderValue, RETRY_AFTER},
+    Body, Response,
+};
+use once_cell::sync::Lazy;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+/// Window of the `requests_per_minute` limit.
+const REQUEST_WINDOW: Duration = Duration::from_secs(60);
+
+/// Window of the `cost_per_hour` quota.
+const COST_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Pixel count of an image costing one unit per step, i.e. 512x512.
+const UNIT_PIXELS: u64 = 512 * 512;
+
+/// Number of sample steps of a request that does not set them.
+pub(crate) const DEFAULT_STEPS: u64 = 20;
+
+/// Height and width of the images of a request that does not set them.
+pub(crate) const DEFAULT_SIZE: u64 = 512;
+
+// recent requests and costs of each API key, keyed by the key name
+static USAGE: Lazy<Mutex<HashMap<String, KeyUsage>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The rate limits of an API key. A limit that is not set is not enforced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct RateLimits {
+    /// Maximum number of image requests in any minute.
+    pub(crate) requests_per_minute: Option<u64>,
+    /// Maximum cost of the image requests in any hour. See [`image_cost`].
+    pub(crate) cost_per_hour: Option<u64>,
+}
+
+/// The recent amounts counted against a limit, oldest first.
+#[derive(Debug, Default)]
+struct Window {
+    entries: VecDeque<(Instant, u64)>,
+}
+impl Window {
+    /// Drop the entries older than the window.
+    fn expire(&mut self, now: Instant, length: Duration) {
+        while let Some((at, _)) = self.entries.front() {
+            if now.duration_since(*at) < length {
+                break;
+            }
+            self.entries.pop_front();
+        }
+    }
+
+    fn used(&self) -> u64 {
+        self.entries.iter().map(|(_, amount)| amount).sum()
+    }
+
+    /// Time until the amount fits under the limit, as the oldest entries expire.
+    fn wait(&self, now: Instant, length: Duration, limit: u64, amount: u64) -> Duration {
+        let mut used = self.used();
+        let mut wait = Duration::ZERO;
+        for (at, value) in self.entries.iter() {
+            if used + amount <= limit {
+                break;
+            }
+            used -= value;
+            wait = (*at + length).saturating_duration_since(now);
+        }
+
+        wait
+    }
+
+    /// Remove the entry counted at the given instant, if any.
+    fn remove(&mut self, at: Instant, amount: u64) {
+        if let Some(i) = self.entries.iter().position(|entry| *entry == (at, amount)) {
+            self.entries.remove(i);
+        }
+    }
+
+    /// Time until every entry expires.
+    fn reset(&self, now: Instant, length: Duration) -> Duration {
+        match self.entries.back() {
+            Some((at, _)) => (*at + length).saturating_duration_since(now),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct KeyUsage {
+    requests: Window,
+    costs: Window,
+}
+
+/// The state of a limit, sent in the `x-ratelimit-{limit,remaining,reset}-{name}` headers.
+#[derive(Debug, Clone)]
+struct LimitState {
+    name: &'static str,
+    limit: u64,
+    remaining: u64,
+    reset: Duration,
+}
+
+/// The state of the limits of an API key after a request.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RateLimitStatus {
+    states: Vec<LimitState>,
+}
+impl RateLimitStatus {
+    /// Add the `x-ratelimit-*` headers to the response.
+    pub(crate) fn apply(&self, response: &mut Response<Body>) {
+        let headers = response.headers_mut();
+        for state in self.states.iter() {
+            let values = [
+                ("limit", state.limit),
+                ("remaining", state.remaining),
+                ("reset", seconds(state.reset)),
+            ];
+            for (field, value) in values {
+                let name = format!("x-ratelimit-{}-{}", field, state.name);
+                if let Ok(name) = HeaderName::try_from(name) {
+                    headers.insert(name, HeaderValue::from(value));
+                }
+            }
+        }
+    }
+}
+
+/// An image request counted against the limits of its API key. The request is refunded when the
+/// charge is dropped, unless it is kept once the images are generated, so that a request rejected
+/// by the queue or failed by the backend is not counted.
+#[derive(Debug, Default)]
+pub(crate) struct Charge {
+    /// The key name and the instant the request was counted at, if it was counted.
+    entry: Option<(String, Instant)>,
+    cost: u64,
+    status: RateLimitStatus,
+}
+impl Charge {
+    /// The state of the limits after the request was counted.
+    pub(crate) fn status(&self) -> &RateLimitStatus {
+        &self.status
+    }
+
+    /// Keep the request counted.
+    pub(crate) fn keep(mut self) {
+        self.entry = None;
+    }
+}
+impl Drop for Charge {
+    fn drop(&mut self) {
+        if let Some((key, at)) = self.entry.take() {
+            if let Some(usage) = usage().get_mut(&key) {
+                usage.requests.remove(at, 1);
+                usage.costs.remove(at, self.cost);
+            }
+        }
+    }
+}
+
+/// Error returned if a request exceeds the limits of its API key.
+#[derive(Error, Debug)]
+pub(crate) enum RateLimitError {
+    /// The limit is reached for now.
+    #[error("{message}")]
+    Exceeded {
+        message: String,
+        retry_after: Duration,
+        status: RateLimitStatus,
+    },
+    /// The request alone costs more than the quota.
+    #[error("The request costs {cost} units, more than the quota of {limit} units per hour of the API key `{key}`. Lower `n`, `steps` or the image size.")]
+    TooExpensive { key: String, cost: u64, limit: u64 },
+}
+impl RateLimitError {
+    /// Build the error response: `429 Too Many Requests` with the `Retry-After` header for a
+    /// reached limit, or `400 Bad Request` for a request that can never fit in the quota.
+    pub(crate) fn into_response(self) -> Response<Body> {
+        match self {
+            RateLimitError::Exceeded {
+                message,
+                retry_after,
+                status,
+            } => {
+                let mut response = error::too_many_requests(message);
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(seconds(retry_after).max(1)));
+                status.apply(&mut response);
+
+                response
+            }
+            e @ RateLimitError::TooExpensive { .. } => ServerError::InvalidRequest {
+                message: e.to_string(),
+                param: None,
+                code: Some("cost_exceeds_quota".to_string()),
+            }
+            .into_response(),
+        }
+    }
+}
+
+fn usage() -> MutexGuard<'static, HashMap<String, KeyUsage>> {
+    match USAGE.lock() {
+        Ok(usage) => usage,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Round the duration up to whole seconds.
+fn seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// The cost of an image request: one unit per sample step of a 512x512 image, scaled by the
+/// number of images and their pixel count, and rounded up. The parameters not set by the request
+/// take the backend defaults.
+pub(crate) fn image_cost(
+    n: Option<u64>,
+    steps: Option<usize>,
+    height: Option<usize>,
+    width: Option<usize>,
+) -> u64 {
+    let n = n.unwrap_or(1);
+    let steps = steps.map(|steps| steps as u64).unwrap_or(DEFAULT_STEPS);
+    let pixels = height.map(|h| h as u64).unwrap_or(DEFAULT_SIZE)
+        * width.map(|w| w as u64).unwrap_or(DEFAULT_SIZE);
+
+    (n * steps * pixels).div_ceil(UNIT_PIXELS)
+}
+
+/// Count an image request of the given cost against the limits of its API key, if any.
+///
+/// A rejected request is not counted, and the returned [`Charge`] refunds the request unless it
+/// is kept. Requests without a key, e.g. when no key is configured, are not limited.
+pub(crate) fn acquire(key_info: Option<&KeyInfo>, cost: u64) -> Result<Charge, RateLimitError> {
+    let key_info = match key_info {
+        Some(key_info) => key_info,
+        None => return Ok(Charge::default()),
+    };
+    let limits = key_info.limits;
+    if limits == RateLimits::default() {
+        return Ok(Charge::default());
+    }
+
+    if let Some(limit) = limits.cost_per_hour {
+        if cost > limit {
+            return Err(RateLimitError::TooExpensive {
+                key: key_info.name.clone(),
+                cost,
+                limit,
+            });
+        }
+    }
+
+    let now = Instant::now();
+    let mut usage = usage();
+    let usage = usage.entry(key_info.name.clone()).or_default();
+
+    let mut windows = [
+        (
+            "requests",
+            "requests per minute",
+            limits.requests_per_minute,
+            &mut usage.requests,
+            REQUEST_WINDOW,
+            1,
+        ),
+        (
+            "cost",
+            "cost units per hour",
+            limits.cost_per_hour,
+            &mut usage.costs,
+            COST_WINDOW,
+            cost,
+        ),
+    ];
+
+    // check every limit before counting the request against any of them
+    let mut exceeded = Vec::new();
+    let mut retry_after = Duration::ZERO;
+    for (_, description, limit, window, length, amount) in windows.iter_mut() {
+        if let Some(limit) = limit {
+            window.expire(now, *length);
+            if window.used() + *amount > *limit {
+                exceeded.push(format!("{} {}", limit, description));
+                retry_after = retry_after.max(window.wait(now, *length, *limit, *amount));
+            }
+        }
+    }
+
+    if exceeded.is_empty() {
+        for (_, _, limit, window, _, amount) in windows.iter_mut() {
+            if limit.is_some() {
+                window.entries.push_back((now, *amount));
+            }
+        }
+    }
+
+    let states = windows
+        .iter()
+        .filter_map(|(name, _, limit, window, length, _)| {
+            limit.map(|limit| LimitState {
+                name,
+                limit,
+                remaining: limit.saturating_sub(window.used()),
+                reset: window.reset(now, *length),
+            })
+        })
+        .collect();
+    let status = RateLimitStatus { states };
+
+    match exceeded.is_empty() {
+        true => Ok(Charge {
+            entry: Some((key_info.name.clone(), now)),
+            cost,
+            status,
+        }),
+        false => {
+            let message = format!(
+                "Rate limit reached for the API key `{}`: {}. Please retry in {} seconds.",
+                key_info.name,
+                exceeded.join(" and "),
+                seconds(retry_after).max(1)
+            );
+
+            // log
+            warn!(target: "stdout", "{}", &message);
+
+            Err(RateLimitError::Exceeded {
+                message,
+                retry_after,
+                status,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An instant late enough for the entries of the windows to be in the past of the monotonic
+    /// clock.
+    fn later() -> Instant {
+        Instant::now() + COST_WINDOW
+    }
+
+    fn window(now: Instant, ages: &[(u64, u64)]) -> Window {
+        Window {
+            entries: ages
+                .iter()
+                .map(|(age, amount)| (now - Duration::from_secs(*age), *amount))
+                .collect(),
+        }
+    }
+
+    fn key_info(
+        name: &str,
+        requests_per_minute: Option<u64>,
+        cost_per_hour: Option<u64>,
+    ) -> KeyInfo {
+        KeyInfo {
+            name: name.to_string(),
+            scopes: Vec::new(),
+            limits: RateLimits {
+                requests_per_minute,
+                cost_per_hour,
+            },
+        }
+    }
+
+    #[test]
+    fn expired_entries_are_dropped() {
+        let now = later();
+        let mut window = window(now, &[(70, 1), (60, 1), (30, 1)]);
+
+        window.expire(now, REQUEST_WINDOW);
+
+        assert_eq!(window.used(), 1);
+    }
+
+    #[test]
+    fn the_wait_lasts_until_enough_entries_expire() {
+        let now = later();
+        let window = window(now, &[(50, 1), (40, 1), (10, 1)]);
+
+        assert_eq!(window.wait(now, REQUEST_WINDOW, 4, 1), Duration::ZERO);
+        assert_eq!(
+            window.wait(now, REQUEST_WINDOW, 3, 1),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            window.wait(now, REQUEST_WINDOW, 2, 1),
+            Duration::from_secs(20)
+        );
+    }
+
+    #[test]
+    fn the_window_resets_when_the_last_entry_expires() {
+        let now = later();
+
+        assert_eq!(window(now, &[]).reset(now, REQUEST_WINDOW), Duration::ZERO);
+        assert_eq!(
+            window(now, &[(50, 1), (10, 1)]).reset(now, REQUEST_WINDOW),
+            Duration::from_secs(50)
+        );
+    }
+
+    #[test]
+    fn the_cost_scales_with_the_images_the_steps_and_the_pixels() {
+        assert_eq!(image_cost(None, None, None, None), DEFAULT_STEPS);
+        assert_eq!(image_cost(Some(2), Some(30), Some(1024), Some(1024)), 240);
+        assert_eq!(image_cost(Some(1), Some(1), Some(64), Some(64)), 1);
+    }
+
+    #[test]
+    fn requests_over_the_limit_are_rejected() {
+        let key_info = key_info("limits-test-requests", Some(2), None);
+
+        acquire(Some(&key_info), 1).unwrap().keep();
+        acquire(Some(&key_info), 1).unwrap().keep();
+
+        match acquire(Some(&key_info), 1) {
+            Err(RateLimitError::Exceeded { retry_after, .. }) => {
+                assert!(retry_after > Duration::ZERO && retry_after <= REQUEST_WINDOW)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn requests_costing_more_than_the_quota_are_rejected() {
+        let key_info = key_info("limits-test-quota", None, Some(100));
+
+        assert!(matches!(
+            acquire(Some(&key_info), 101),
+            Err(RateLimitError::TooExpensive { .. })
+        ));
+        acquire(Some(&key_info), 100).unwrap().keep();
+        assert!(matches!(
+            acquire(Some(&key_info), 1),
+            Err(RateLimitError::Exceeded { .. })
+        ));
+    }
+
+    #[test]
+    fn dropped_charges_are_refunded() {
+        let key_info = key_info("limits-test-refund", Some(1), Some(100));
+
+        drop(acquire(Some(&key_info), 100).unwrap());
+
+        acquire(Some(&key_info), 100).unwrap().keep();
+    }
+
+    #[test]
+    fn requests_without_limits_are_not_counted() {
+        assert!(acquire(None, u64::MAX).is_ok());
+        assert!(acquire(Some(&key_info("limits-test-none", None, None)), u64::MAX).is_ok());
+    }
+}
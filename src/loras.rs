@@ -0,0 +1,499 @@
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+/// Extensions of the LoRA model files in the lora model directory.
+const LORA_EXTENSIONS: [&str; 3] = ["safetensors", "gguf", "ckpt"];
+
+/// Maximum size of the safetensors header read for the metadata.
+const MAX_SAFETENSORS_HEADER_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Number of tags reported as trigger words when the adapter does not name any.
+const MAX_TRIGGER_WORDS: usize = 10;
+
+/// Size of the chunks the LoRA files are hashed by. The hashing task yields to the other tasks
+/// of the runtime between two chunks.
+const DIGEST_CHUNK_SIZE: usize = 1024 * 1024;
+
+// sha256 digests and safetensors metadata of the LoRA files, keyed by path
+static FILES: Lazy<Mutex<HashMap<PathBuf, CachedFile>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// LoRA files waiting to be hashed, and whether the hashing task is running
+static PENDING: Lazy<Mutex<PendingDigests>> = Lazy::new(|| Mutex::new(PendingDigests::default()));
+
+#[derive(Debug, Default)]
+struct PendingDigests {
+    paths: BTreeSet<PathBuf>,
+    running: bool,
+}
+
+/// The digest and the metadata of a file, valid as long as the size and the modification time of
+/// the file match.
+#[derive(Debug)]
+struct CachedFile {
+    size: u64,
+    modified: SystemTime,
+    sha256: Option<String>,
+    metadata: Option<BTreeMap<String, String>>,
+}
+impl CachedFile {
+    fn is_current(&self, file_metadata: &fs::Metadata) -> bool {
+        self.size == file_metadata.len() && file_metadata.modified().ok() == Some(self.modified)
+    }
+}
+
+/// A LoRA adapter found in the lora model directory.
+#[derive(Debug, Serialize)]
+pub(crate) struct LoraInfo {
+    /// The name of the adapter, as referenced in the `<lora:name:weight>` prompt syntax.
+    pub(crate) id: String,
+    /// The object type, which is always `lora`.
+    pub(crate) object: String,
+    /// The file name of the adapter.
+    pub(crate) filename: String,
+    /// The size of the file in bytes.
+    pub(crate) bytes: u64,
+    /// The hex-encoded sha256 digest of the file, absent until it is computed in the background.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) sha256: Option<String>,
+    /// The base model the adapter was trained on, if recorded in the metadata.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) base_model: Option<String>,
+    /// The words triggering the adapter, if recorded in the metadata.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) trigger_words: Vec<String>,
+    /// The metadata embedded in the file. Only safetensors files embed metadata.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) metadata: BTreeMap<String, String>,
+}
+
+/// Return the paths of the LoRA files in the directory, sorted by name.
+fn lora_files(lora_model_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(lora_model_dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .map(|ext| LORA_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+                    .unwrap_or(false)
+        })
+        .collect();
+    paths.sort();
+
+    Ok(paths)
+}
+
+fn lora_name(path: &Path) -> Option<String> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .map(|stem| stem.to_string())
+}
+
+/// Return the sorted names of the LoRA models in the directory.
+pub(crate) fn lora_names(lora_model_dir: impl AsRef<Path>) -> Vec<String> {
+    match lora_files(lora_model_dir.as_ref()) {
+        Ok(paths) => paths.iter().filter_map(|path| lora_name(path)).collect(),
+        Err(e) => {
+            // log
+            warn!(target: "stdout", "Failed to read the lora model directory {}. {}", lora_model_dir.as_ref().display(), e);
+
+            Vec::new()
+        }
+    }
+}
+
+/// Describe the LoRA models in the directory, with their digest and embedded metadata.
+pub(crate) fn list_loras(lora_model_dir: impl AsRef<Path>) -> io::Result<Vec<LoraInfo>> {
+    let mut loras = Vec::new();
+    for path in lora_files(lora_model_dir.as_ref())? {
+        let (id, filename) = match (lora_name(&path), path.file_name().and_then(|f| f.to_str())) {
+            (Some(id), Some(filename)) => (id, filename.to_string()),
+            _ => continue,
+        };
+
+        let file_metadata = match fs::metadata(&path) {
+            Ok(file_metadata) => file_metadata,
+            Err(e) => {
+                // log
+                warn!(target: "stdout", "Failed to read the lora file {}. {}", path.display(), e);
+
+                continue;
+            }
+        };
+
+        let sha256 = cached_digest(&path, &file_metadata);
+        if sha256.is_none() {
+            schedule_digest(&path);
+        }
+
+        let metadata = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("safetensors") => {
+                cached_metadata(&path, &file_metadata)
+            }
+            _ => BTreeMap::new(),
+        };
+
+        loras.push(LoraInfo {
+            id,
+            object: "lora".to_string(),
+            filename,
+            bytes: file_metadata.len(),
+            sha256,
+            base_model: base_model(&metadata),
+            trigger_words: trigger_words(&metadata),
+            metadata,
+        });
+    }
+
+    Ok(loras)
+}
+
+fn files() -> MutexGuard<'static, HashMap<PathBuf, CachedFile>> {
+    match FILES.lock() {
+        Ok(files) => files,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn pending() -> MutexGuard<'static, PendingDigests> {
+    match PENDING.lock() {
+        Ok(pending) => pending,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Return the cache entry of the file, replacing the entry of a previous version of the file.
+fn cache_entry<'a>(
+    files: &'a mut HashMap<PathBuf, CachedFile>,
+    path: &Path,
+    size: u64,
+    modified: SystemTime,
+) -> &'a mut CachedFile {
+    let cached = files.entry(path.to_path_buf()).or_insert(CachedFile {
+        size,
+        modified,
+        sha256: None,
+        metadata: None,
+    });
+    if cached.size != size || cached.modified != modified {
+        *cached = CachedFile {
+            size,
+            modified,
+            sha256: None,
+            metadata: None,
+        };
+    }
+
+    cached
+}
+
+/// Return the sha256 digest of the file, if computed for the current version of the file.
+fn cached_digest(path: &Path, file_metadata: &fs::Metadata) -> Option<String> {
+    files()
+        .get(path)
+        .filter(|cached| cached.is_current(file_metadata))
+        .and_then(|cached| cached.sha256.clone())
+}
+
+/// Return the metadata of the safetensors file, whose header is only read once per version of
+/// the file. A header that cannot be read counts as empty metadata.
+fn cached_metadata(path: &Path, file_metadata: &fs::Metadata) -> BTreeMap<String, String> {
+    if let Some(metadata) = files()
+        .get(path)
+        .filter(|cached| cached.is_current(file_metadata))
+        .and_then(|cached| cached.metadata.clone())
+    {
+        return metadata;
+    }
+
+    let metadata = match safetensors_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            // log
+            warn!(target: "stdout", "Failed to read the metadata of the lora file {}. {}", path.display(), e);
+
+            BTreeMap::new()
+        }
+    };
+
+    if let Ok(modified) = file_metadata.modified() {
+        cache_entry(&mut files(), path, file_metadata.len(), modified).metadata =
+            Some(metadata.clone());
+    }
+
+    metadata
+}
+
+/// Hash the LoRA files of the directory in the background, so that their digests are ready for
+/// the first listing.
+pub(crate) fn hash_loras(lora_model_dir: impl AsRef<Path>) {
+    match lora_files(lora_model_dir.as_ref()) {
+        Ok(paths) => paths.iter().for_each(|path| schedule_digest(path)),
+        Err(e) => {
+            // log
+            warn!(target: "stdout", "Failed to read the lora model directory {}. {}", lora_model_dir.as_ref().display(), e);
+        }
+    }
+}
+
+/// Queue the file for the hashing task, and start the task if it is not running.
+fn schedule_digest(path: &Path) {
+    let mut pending = pending();
+    pending.paths.insert(path.to_path_buf());
+
+    if !pending.running {
+        pending.running = true;
+        tokio::spawn(hash_pending());
+    }
+}
+
+/// Hash the queued files one by one, until none is left.
+async fn hash_pending() {
+    loop {
+        let path = {
+            let mut pending = pending();
+            match pending.paths.pop_first() {
+                Some(path) => path,
+                None => {
+                    pending.running = false;
+                    return;
+                }
+            }
+        };
+
+        let file_metadata = match fs::metadata(&path) {
+            Ok(file_metadata) => file_metadata,
+            Err(e) => {
+                // log
+                warn!(target: "stdout", "Failed to read the lora file {}. {}", path.display(), e);
+
+                continue;
+            }
+        };
+        if cached_digest(&path, &file_metadata).is_some() {
+            continue;
+        }
+
+        match (file_metadata.modified(), digest(&path).await) {
+            (Ok(modified), Ok(sha256)) => {
+                cache_entry(&mut files(), &path, file_metadata.len(), modified).sha256 =
+                    Some(sha256);
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                // log
+                warn!(target: "stdout", "Failed to hash the lora file {}. {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Compute the sha256 digest of the file, yielding to the other tasks between two chunks, as the
+/// runtime has a single thread.
+async fn digest(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; DIGEST_CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+
+        tokio::task::yield_now().await;
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Read the `__metadata__` entry of the safetensors header.
+///
+/// A safetensors file starts with the size of its JSON header as a little-endian `u64`.
+fn safetensors_metadata(path: &Path) -> io::Result<BTreeMap<String, String>> {
+    let mut file = File::open(path)?;
+
+    let mut size = [0u8; 8];
+    file.read_exact(&mut size)?;
+    let size = u64::from_le_bytes(size);
+    if size > MAX_SAFETENSORS_HEADER_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("The safetensors header is too large: {} bytes.", size),
+        ));
+    }
+
+    let mut header = vec![0; size as usize];
+    file.read_exact(&mut header)?;
+
+    let mut header: serde_json::Value = serde_json::from_slice(&header)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let metadata = match header.get_mut("__metadata__").map(serde_json::Value::take) {
+        Some(serde_json::Value::Object(map)) => map
+            .into_iter()
+            .filter_map(|(key, value)| match value {
+                serde_json::Value::String(value) => Some((key, value)),
+                _ => None,
+            })
+            .collect(),
+        _ => BTreeMap::new(),
+    };
+
+    Ok(metadata)
+}
+
+/// Find the base model in the metadata written by the common training tools.
+fn base_model(metadata: &BTreeMap<String, String>) -> Option<String> {
+    [
+        "modelspec.architecture",
+        "ss_base_model_version",
+        "ss_sd_model_name",
+    ]
+    .iter()
+    .find_map(|key| metadata.get(*key))
+    .filter(|value| !value.is_empty())
+    .cloned()
+}
+
+/// Find the trigger words in the metadata: the trigger phrase if any, otherwise the most frequent
+/// tags of the training dataset.
+fn trigger_words(metadata: &BTreeMap<String, String>) -> Vec<String> {
+    if let Some(trigger_phrase) = metadata.get("modelspec.trigger_phrase") {
+        return trigger_phrase
+            .split(',')
+            .map(|word| word.trim().to_string())
+            .filter(|word| !word.is_empty())
+            .collect();
+    }
+
+    // `ss_tag_frequency` maps each dataset directory to the frequency of its tags
+    let tag_frequency: HashMap<String, HashMap<String, u64>> = match metadata
+        .get("ss_tag_frequency")
+        .and_then(|value| serde_json::from_str(value).ok())
+    {
+        Some(tag_frequency) => tag_frequency,
+        None => return Vec::new(),
+    };
+
+    let mut frequency: HashMap<String, u64> = HashMap::new();
+    for tags in tag_frequency.into_values() {
+        for (tag, count) in tags {
+            *frequency.entry(tag.trim().to_string()).or_default() += count;
+        }
+    }
+
+    let mut tags: Vec<(String, u64)> = frequency
+        .into_iter()
+        .filter(|(tag, _)| !tag.is_empty())
+        .collect();
+    tags.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    tags.into_iter()
+        .take(MAX_TRIGGER_WORDS)
+        .map(|(tag, _)| tag)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Write a safetensors file holding no tensor, only the given metadata.
+    fn write_safetensors(path: &Path, metadata: serde_json::Value) {
+        let header = serde_json::json!({ "__metadata__": metadata }).to_string();
+
+        let mut file = File::create(path).unwrap();
+        file.write_all(&(header.len() as u64).to_le_bytes())
+            .unwrap();
+        file.write_all(header.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn the_metadata_is_read_once_per_version_of_the_file() {
+        let dir = std::env::temp_dir().join(format!("sd-api-server-loras-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pixel-art.safetensors");
+
+        write_safetensors(
+            &path,
+            serde_json::json!({
+                "modelspec.architecture": "stable-diffusion-xl-v1-base/lora",
+                "modelspec.trigger_phrase": "pixel art, 8-bit",
+            }),
+        );
+        fs::write(dir.join("notes.txt"), "not a lora").unwrap();
+
+        assert_eq!(lora_names(&dir), vec!["pixel-art"]);
+
+        let file_metadata = fs::metadata(&path).unwrap();
+        let metadata = cached_metadata(&path, &file_metadata);
+        assert_eq!(
+            base_model(&metadata).as_deref(),
+            Some("stable-diffusion-xl-v1-base/lora")
+        );
+        assert_eq!(trigger_words(&metadata), vec!["pixel art", "8-bit"]);
+
+        // the cached metadata is returned while the file is unchanged
+        files().get_mut(&path).unwrap().metadata = Some(BTreeMap::new());
+        assert!(cached_metadata(&path, &file_metadata).is_empty());
+
+        // a new version of the file is read again
+        write_safetensors(
+            &path,
+            serde_json::json!({ "ss_base_model_version": "sd_v1" }),
+        );
+        let file_metadata = fs::metadata(&path).unwrap();
+        assert_eq!(
+            base_model(&cached_metadata(&path, &file_metadata)).as_deref(),
+            Some("sd_v1")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_trigger_words_are_the_most_frequent_tags_without_a_trigger_phrase() {
+        let tag_frequency = serde_json::json!({
+            "10_pixel": { "pixel art": 12, " sprite ": 5, "": 3 },
+            "5_icons": { "sprite": 4, "icon": 9 },
+        });
+        let metadata =
+            BTreeMap::from([("ss_tag_frequency".to_string(), tag_frequency.to_string())]);
+
+        assert_eq!(
+            trigger_words(&metadata),
+            vec!["pixel art", "icon", "sprite"]
+        );
+        assert!(trigger_words(&BTreeMap::new()).is_empty());
+    }
+
+    #[test]
+    fn oversized_safetensors_headers_are_rejected() {
+        let path = std::env::temp_dir().join(format!(
+            "sd-api-server-oversized-{}.safetensors",
+            std::process::id()
+        ));
+        fs::write(&path, (MAX_SAFETENSORS_HEADER_SIZE + 1).to_le_bytes()).unwrap();
+
+        let e = safetensors_metadata(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -2,19 +2,35 @@
 extern crate log;
 
 mod backend;
+mod config;
 mod error;
+mod jobs;
+mod keys;
+mod limits;
+mod loras;
+mod metrics;
+mod models;
+mod presets;
+mod queue;
+mod usage;
 mod utils;
 
 use anyhow::Result;
-use clap::{ArgGroup, Parser, ValueEnum};
+use clap::{
+    parser::ValueSource, ArgGroup, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum,
+};
+use config::FileConfig;
 use error::ServerError;
 use hyper::{
     body::HttpBody,
     server::conn::AddrStream,
     service::{make_service_fn, service_fn},
-    Body, Request, Response, Server,
+    Body, Method, Request, Response, Server,
 };
+use models::ModelConfig;
 use once_cell::sync::OnceCell;
+use queue::{GenerationQueue, GENERATION_QUEUE};
+use serde::{Deserialize, Serialize};
 use std::{
     net::{IpAddr, SocketAddr},
     path::PathBuf,
@@ -30,16 +46,14 @@ const DEFAULT_PORT: &str = "8080";
 
 // socket address
 pub(crate) static DOWNLOAD_URL_PREFIX: OnceCell<Url> = OnceCell::new();
-// API key
-pub(crate) static LLAMA_API_KEY: OnceCell<String> = OnceCell::new();
 
 #[derive(Debug, Parser)]
 #[command(name = "LlamaEdge-StableDiffusion API Server", version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"), about = "LlamaEdge-Stable-Diffusion API Server")]
-#[command(group = ArgGroup::new("model_group").multiple(false).required(true).args(&["model", "diffusion_model"]))]
+#[command(group = ArgGroup::new("model_group").multiple(false).args(&["model", "diffusion_model"]))]
 #[command(group = ArgGroup::new("socket_address_group").multiple(false).args(&["socket_addr", "port"]))]
 struct Cli {
     /// Sets the model name.
-    #[arg(short, long, required = true)]
+    #[arg(short, long, default_value = "")]
     model_name: String,
     /// Path to full model
     #[arg(long, default_value = "", group = "model_group")]
@@ -86,6 +100,181 @@ struct Cli {
     /// Download URL prefix, format: `http(s)://{IPv4_address}:{port}` or `http(s)://{domain}:{port}`
     #[arg(long)]
     download_url_prefix: Option<String>,
+    /// Maximum number of image requests waiting in the generation queue. Requests beyond the limit are rejected with `503 Service Unavailable`.
+    #[arg(long, default_value_t = queue::DEFAULT_MAX_QUEUE_SIZE)]
+    max_queue_size: usize,
+    /// Path to the JSON file of the named generation presets. The presets changed through the `/v1/presets` endpoints are saved to the file.
+    #[arg(long)]
+    presets: Option<PathBuf>,
+    /// Path to a TOML, YAML or JSON file of named API keys and their scopes. The file is reloaded when it changes.
+    #[arg(long)]
+    api_keys: Option<PathBuf>,
+    /// Path to the JSON Lines file recording the usage of the completed image requests, aggregated by the `/v1/usage` endpoint.
+    #[arg(long, default_value = usage::DEFAULT_USAGE_FILE)]
+    usage_file: PathBuf,
+    /// Comma-separated paths served without an API key for GET requests, e.g. `/echo,/v1/files/download/`. A path ending with `/` matches every path under it. `GET /health` is always public.
+    #[arg(long, value_delimiter = ',')]
+    public_routes: Vec<String>,
+    /// Path to a TOML or YAML config file. The CLI options override the settings of the file.
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
+impl Cli {
+    /// Apply the settings of the config file to the options not given on the command line.
+    fn merge_config(&mut self, matches: &ArgMatches, file_config: FileConfig) {
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        merge(
+            &mut self.model_name,
+            from_cli("model_name"),
+            file_config.model_name,
+        );
+        merge(&mut self.model, from_cli("model"), file_config.model);
+        merge(
+            &mut self.diffusion_model,
+            from_cli("diffusion_model"),
+            file_config.diffusion_model,
+        );
+        merge(&mut self.vae, from_cli("vae"), file_config.vae);
+        merge(&mut self.clip_l, from_cli("clip_l"), file_config.clip_l);
+        merge(&mut self.t5xxl, from_cli("t5xxl"), file_config.t5xxl);
+        merge(
+            &mut self.lora_model_dir,
+            from_cli("lora_model_dir"),
+            file_config.lora_model_dir.map(Some),
+        );
+        merge(
+            &mut self.control_net,
+            from_cli("control_net"),
+            file_config.control_net.map(Some),
+        );
+        merge(
+            &mut self.control_net_cpu,
+            from_cli("control_net_cpu"),
+            file_config.control_net_cpu,
+        );
+        merge(&mut self.threads, from_cli("threads"), file_config.threads);
+        merge(
+            &mut self.clip_on_cpu,
+            from_cli("clip_on_cpu"),
+            file_config.clip_on_cpu,
+        );
+        merge(
+            &mut self.vae_on_cpu,
+            from_cli("vae_on_cpu"),
+            file_config.vae_on_cpu,
+        );
+        merge(&mut self.task, from_cli("task"), file_config.task);
+        // a port given on the command line wins over a socket address given in the file
+        merge(
+            &mut self.socket_addr,
+            from_cli("socket_addr") || from_cli("port"),
+            file_config.socket_addr.map(Some),
+        );
+        merge(&mut self.port, from_cli("port"), file_config.port);
+        merge(
+            &mut self.download_url_prefix,
+            from_cli("download_url_prefix"),
+            file_config.download_url_prefix.map(Some),
+        );
+        merge(
+            &mut self.max_queue_size,
+            from_cli("max_queue_size"),
+            file_config.max_queue_size,
+        );
+        merge(
+            &mut self.presets,
+            from_cli("presets"),
+            file_config.presets.map(Some),
+        );
+        merge(
+            &mut self.api_keys,
+            from_cli("api_keys"),
+            file_config.api_keys.map(Some),
+        );
+        merge(
+            &mut self.usage_file,
+            from_cli("usage_file"),
+            file_config.usage_file,
+        );
+        merge(
+            &mut self.public_routes,
+            from_cli("public_routes"),
+            file_config.public_routes,
+        );
+    }
+}
+
+impl Cli {
+    /// Apply the model options given on the command line to the model of the `models` list of the
+    /// config file.
+    fn merge_model(&self, matches: &ArgMatches, model_config: &mut ModelConfig) {
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        override_with(
+            &mut model_config.name,
+            from_cli("model_name"),
+            &self.model_name,
+        );
+        override_with(&mut model_config.model, from_cli("model"), &self.model);
+        override_with(
+            &mut model_config.diffusion_model,
+            from_cli("diffusion_model"),
+            &self.diffusion_model,
+        );
+        override_with(&mut model_config.vae, from_cli("vae"), &self.vae);
+        override_with(&mut model_config.clip_l, from_cli("clip_l"), &self.clip_l);
+        override_with(&mut model_config.t5xxl, from_cli("t5xxl"), &self.t5xxl);
+        override_with(
+            &mut model_config.lora_model_dir,
+            from_cli("lora_model_dir"),
+            &self.lora_model_dir,
+        );
+        override_with(
+            &mut model_config.control_net,
+            from_cli("control_net"),
+            &self.control_net,
+        );
+        override_with(
+            &mut model_config.control_net_cpu,
+            from_cli("control_net_cpu"),
+            &self.control_net_cpu,
+        );
+        override_with(
+            &mut model_config.clip_on_cpu,
+            from_cli("clip_on_cpu"),
+            &self.clip_on_cpu,
+        );
+        override_with(
+            &mut model_config.vae_on_cpu,
+            from_cli("vae_on_cpu"),
+            &self.vae_on_cpu,
+        );
+        override_with(
+            &mut model_config.threads,
+            from_cli("threads"),
+            &self.threads,
+        );
+        override_with(&mut model_config.task, from_cli("task"), &self.task);
+    }
+}
+
+/// Replace the value with the one of the command line, if given there.
+fn override_with<T: Clone>(value: &mut T, from_cli: bool, cli_value: &T) {
+    if from_cli {
+        *value = cli_value.clone();
+    }
+}
+
+/// Replace the option with the value of the config file, unless it is given on the command line.
+fn merge<T>(option: &mut T, from_cli: bool, file_value: Option<T>) {
+    if from_cli {
+        return;
+    }
+
+    if let Some(value) = file_value {
+        *option = value;
+    }
 }
 
 #[allow(clippy::needless_return)]
@@ -101,126 +290,152 @@ async fn main() -> Result<(), ServerError> {
     wasi_logger::Logger::install().expect("failed to install wasi_logger::Logger");
     log::set_max_level(log_level.into());
 
-    if let Ok(api_key) = std::env::var("API_KEY") {
-        // define a const variable for the API key
-        if let Err(e) = LLAMA_API_KEY.set(api_key) {
-            let err_msg = format!("Failed to set API key. {}", e);
-
-            error!(target: "stdout", "{}", err_msg);
+    // parse the command line arguments
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
-            return Err(ServerError::Operation(err_msg));
+    // load the config file
+    let mut file_config = match &cli.config {
+        Some(config_file) => config::load_config(config_file)?,
+        None => FileConfig::default(),
+    };
+    let file_api_key = file_config.api_key.take();
+    let file_models = std::mem::take(&mut file_config.models);
+    let file_defaults = file_config.defaults.take();
+    if std::env::var("LLAMA_LOG").is_err() {
+        if let Some(log_level) = file_config.log_level.take() {
+            log::set_max_level(log_level.into());
         }
     }
-
-    // parse the command line arguments
-    let cli = Cli::parse();
+    cli.merge_config(&matches, file_config);
 
     // log the version of the server
     info!(target: "stdout", "server version: {}", env!("CARGO_PKG_VERSION"));
 
-    if cli.model_name.is_empty() {
+    // log config file
+    if let Some(config_file) = &cli.config {
+        info!(target: "stdout", "config: {}", config_file.display());
+    }
+
+    // set the API keys
+    if let Some(key_file) = &cli.api_keys {
+        info!(target: "stdout", "api_keys: {}", key_file.display());
+    }
+    if !cli.public_routes.is_empty() {
+        info!(target: "stdout", "public_routes: {}", cli.public_routes.join(","));
+    }
+    keys::init_keys(
+        cli.api_keys.clone(),
+        std::env::var("API_KEY").ok().or(file_api_key),
+        cli.public_routes.clone(),
+    )?;
+
+    // log max queue size
+    info!(target: "stdout", "max_queue_size: {}", cli.max_queue_size);
+
+    // set the generation queue
+    if cli.max_queue_size == 0 {
         return Err(ServerError::ArgumentError(
-            "The value of the '--model-name' option should not be empty.".into(),
+            "The value of the '--max-queue-size' option should be greater than 0.".into(),
         ));
     }
-    // log model name
-    info!(target: "stdout", "model_name: {}", cli.model_name);
+    if GENERATION_QUEUE
+        .set(GenerationQueue::new(cli.max_queue_size))
+        .is_err()
+    {
+        let err_msg = "Failed to set GENERATION_QUEUE.";
 
-    // log task type
-    info!(target: "stdout", "task: {:?}", &cli.task);
+        error!(target: "stdout", "{}", err_msg);
 
-    // log lora model directory
-    if let Some(lora_model_dir) = &cli.lora_model_dir {
-        info!(target: "stdout", "lora_model_dir: {}", lora_model_dir);
-    } else {
-        info!(target: "stdout", "lora_model_dir: None");
+        return Err(ServerError::Operation(err_msg.into()));
     }
 
-    // log control net
-    if let Some(control_net) = &cli.control_net {
-        info!(target: "stdout", "control_net: {}", &control_net);
+    // the model to serve: the one of the `models` list, overridden by the model options given on
+    // the command line, or the one set by the options
+    let model_config = match file_models.into_iter().next() {
+        Some(mut model_config) => {
+            cli.merge_model(&matches, &mut model_config);
+            model_config
+        }
+        None => ModelConfig {
+            name: cli.model_name,
+            model: cli.model,
+            diffusion_model: cli.diffusion_model,
+            vae: cli.vae,
+            clip_l: cli.clip_l,
+            t5xxl: cli.t5xxl,
+            lora_model_dir: cli.lora_model_dir,
+            control_net: cli.control_net,
+            control_net_cpu: cli.control_net_cpu,
+            clip_on_cpu: cli.clip_on_cpu,
+            vae_on_cpu: cli.vae_on_cpu,
+            threads: cli.threads,
+            task: cli.task,
+            defaults: file_defaults.unwrap_or_default(),
+        },
+    };
+    if model_config.name.is_empty() {
+        return Err(ServerError::ArgumentError(
+            "The '--model-name' option and the '--model' or '--diffusion-model' option should be specified, either on the command line or in the config file.".into(),
+        ));
     }
-
-    // log control net on cpu
-    info!(target: "stdout", "control_net_cpu: {}", cli.control_net_cpu);
-
-    // log threads
-    info!(target: "stdout", "threads: {}", cli.threads);
-
-    // log clip on cpu
-    info!(target: "stdout", "clip_on_cpu: {}", cli.clip_on_cpu);
-
-    // log vae on cpu
-    info!(target: "stdout", "vae_on_cpu: {}", cli.vae_on_cpu);
-
-    // Determine which model option is set
-    if !cli.model.is_empty() {
-        info!(target: "stdout", "model: {}", &cli.model);
-
-        // initialize the stable diffusion context
-        llama_core::init_sd_context_with_full_model(
-            &cli.model,
-            cli.lora_model_dir.as_deref(),
-            cli.control_net.as_deref(),
-            cli.control_net_cpu,
-            cli.clip_on_cpu,
-            cli.vae_on_cpu,
-            cli.threads,
-            cli.task.to_sd_context_type(),
-        )
-        .map_err(|e| ServerError::Operation(format!("{}", e)))?;
-    } else if !cli.diffusion_model.is_empty() {
-        // if diffusion_model is not empty, check if diffusion_model is a valid path
-        if !PathBuf::from(&cli.diffusion_model).exists() {
+    match (
+        model_config.model.is_empty(),
+        model_config.diffusion_model.is_empty(),
+    ) {
+        (false, false) => {
             return Err(ServerError::ArgumentError(
-                "The path to the diffusion model does not exist.".into(),
+                "The '--model' and '--diffusion-model' options cannot both be set, on the command line or in the config file.".into(),
             ));
         }
-        info!(target: "stdout", "diffusion model: {}", &cli.diffusion_model);
-
-        // if vae is not empty, check if vae is a valid path
-        if !cli.vae.is_empty() && !PathBuf::from(&cli.vae).exists() {
+        (true, true) => {
             return Err(ServerError::ArgumentError(
-                "The path to the vae does not exist.".into(),
+                "The '--model' or '--diffusion-model' option should be specified, either on the command line or in the config file.".into(),
             ));
         }
-        info!(target: "stdout", "vae: {}", &cli.vae);
+        _ => {}
+    }
 
-        // if clip_l is not empty, check if clip_l is a valid path
-        if !cli.clip_l.is_empty() && !PathBuf::from(&cli.clip_l).exists() {
-            return Err(ServerError::ArgumentError(
-                "The path to the clip-l text encoder does not exist.".into(),
-            ));
-        }
-        info!(target: "stdout", "clip_l: {}", &cli.clip_l);
+    // check the default generation parameters of the model
+    let diffusion_model = model_config.model.is_empty();
+    if let Err(e) = backend::validate::validate_defaults(&model_config.defaults, diffusion_model) {
+        return Err(ServerError::ArgumentError(format!(
+            "Invalid defaults of the model `{}`. {}",
+            model_config.name, e
+        )));
+    }
 
-        // if t5xxl is not empty, check if t5xxl is a valid path
-        if !cli.t5xxl.is_empty() && !PathBuf::from(&cli.t5xxl).exists() {
-            return Err(ServerError::ArgumentError(
-                "The path to the t5xxl text encoder does not exist.".into(),
-            ));
+    // load the presets
+    if let Some(presets_file) = cli.presets {
+        info!(target: "stdout", "presets file: {}", presets_file.display());
+
+        presets::init_presets(presets_file)?;
+
+        for preset in presets::list_presets() {
+            if let Err(e) =
+                backend::validate::validate_defaults(&preset.parameters, diffusion_model)
+            {
+                return Err(ServerError::ArgumentError(format!(
+                    "Invalid preset `{}`. {}",
+                    preset.id, e
+                )));
+            }
         }
-        info!(target: "stdout", "t5xxl: {}", &cli.t5xxl);
-
-        // initialize the stable diffusion context
-        llama_core::init_sd_context_with_standalone_model(
-            &cli.diffusion_model,
-            &cli.vae,
-            &cli.clip_l,
-            &cli.t5xxl,
-            cli.lora_model_dir.as_deref(),
-            cli.control_net.as_deref(),
-            cli.control_net_cpu,
-            cli.clip_on_cpu,
-            cli.vae_on_cpu,
-            cli.threads,
-            cli.task.to_sd_context_type(),
-        )
-        .map_err(|e| ServerError::Operation(format!("{}", e)))?;
-    } else {
-        return Err(ServerError::ArgumentError(
-            "The '--model' or '--diffusion-model' option should be specified.".into(),
-        ));
+    }
+
+    // set the usage file
+    info!(target: "stdout", "usage file: {}", cli.usage_file.display());
+    usage::init_usage(cli.usage_file)?;
+
+    // initialize the model
+    models::init_model(model_config)?;
+
+    // measure the archives directory in the background
+    metrics::spawn_archive_scan();
+
+    // hash the LoRA models in the background
+    if let Some(lora_model_dir) = models::lora_model_dir() {
+        loras::hash_loras(lora_model_dir);
     }
 
     // socket address
@@ -312,11 +527,16 @@ async fn main() -> Result<(), ServerError> {
         async move { Ok::<_, Error>(service_fn(handle_request)) }
     });
 
-    let tcp_listener = TcpListener::bind(addr).await.unwrap();
+    let tcp_listener = TcpListener::bind(addr).await.map_err(|e| {
+        ServerError::Operation(format!("Failed to bind the address {}. {}", addr, e))
+    })?;
     info!(target: "stdout", "Listening on {}", addr);
 
-    let server = Server::from_tcp(tcp_listener.into_std().unwrap())
-        .unwrap()
+    let server = tcp_listener
+        .into_std()
+        .map_err(|e| e.to_string())
+        .and_then(|listener| Server::from_tcp(listener).map_err(|e| e.to_string()))
+        .map_err(|e| ServerError::Operation(format!("Failed to start the server. {}", e)))?
         .serve(new_service);
 
     match server.await {
@@ -325,7 +545,7 @@ async fn main() -> Result<(), ServerError> {
     }
 }
 
-async fn handle_request(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+async fn handle_request(mut req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
     let path_str = req.uri().path();
     let path_buf = PathBuf::from(path_str);
     let mut path_iter = path_buf.iter();
@@ -333,27 +553,15 @@ async fn handle_request(req: Request<Body>) -> Result<Response<Body>, hyper::Err
     let root_path = path_iter.next().unwrap_or_default();
     let root_path = "/".to_owned() + root_path.to_str().unwrap_or_default();
 
-    // check if the API key is valid
-    if let Some(auth_header) = req.headers().get("authorization") {
-        if !auth_header.is_empty() {
-            let auth_header = match auth_header.to_str() {
-                Ok(auth_header) => auth_header,
-                Err(e) => {
-                    let err_msg = format!("Failed to get authorization header: {}", e);
-                    return Ok(error::unauthorized(err_msg));
-                }
-            };
+    // the route and the method counted in the metrics
+    let route = metrics::route_label(req.uri().path());
+    let method = req.method().clone();
 
-            let api_key = auth_header.split(" ").nth(1).unwrap_or_default();
-            info!(target: "stdout", "API Key: {}", api_key);
+    // check the API key
+    if let Some(response) = keys::authorize(&mut req) {
+        metrics::count_request(route, &method, response.status());
 
-            if let Some(stored_api_key) = LLAMA_API_KEY.get() {
-                if api_key != stored_api_key {
-                    let err_msg = "Invalid API key.";
-                    return Ok(error::unauthorized(err_msg));
-                }
-            }
-        }
+        return Ok(response);
     }
 
     // log request
@@ -363,7 +571,11 @@ async fn handle_request(req: Request<Body>) -> Result<Response<Body>, hyper::Err
         let version = format!("{:?}", req.version());
         if req.method() == hyper::http::Method::POST {
             let size: u64 = match req.headers().get("content-length") {
-                Some(content_length) => content_length.to_str().unwrap().parse().unwrap(),
+                Some(content_length) => content_length
+                    .to_str()
+                    .ok()
+                    .and_then(|content_length| content_length.parse().ok())
+                    .unwrap_or_default(),
                 None => 0,
             };
 
@@ -373,12 +585,18 @@ async fn handle_request(req: Request<Body>) -> Result<Response<Body>, hyper::Err
         }
     }
 
+    // panics abort on wasm32-wasip1 and cannot be caught here, so the handlers return error
+    // responses instead of panicking
     let response = match root_path.as_str() {
         "/echo" => Response::new(Body::from("echo test")),
+        keys::HEALTH_ROUTE => health_handler(req.method()),
+        "/metrics" => metrics::metrics_handler(req.method()),
         "/v1" => backend::handle_sd_request(req).await,
         _ => error::invalid_endpoint(root_path.as_str()),
     };
 
+    metrics::count_request(route, &method, response.status());
+
     // log response
     {
         let status_code = response.status();
@@ -411,17 +629,51 @@ async fn handle_request(req: Request<Body>) -> Result<Response<Body>, hyper::Err
     Ok(response)
 }
 
+/// Report that the server is up, e.g. to a load balancer. The route needs no API key.
+///
+/// - `GET /health`: Return `{"status": "ok"}`.
+///
+fn health_handler(method: &Method) -> Response<Body> {
+    if method != Method::GET {
+        return error::method_not_allowed(method);
+    }
+
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .body(Body::from(
+            serde_json::json!({ "status": "ok" }).to_string(),
+        ));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
 /// Task type.
-#[derive(Clone, Debug, Copy, PartialEq, Eq, ValueEnum)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
 enum TaskType {
     /// `text_to_image` task.
     #[value(name = "text2image")]
+    #[serde(rename = "text2image")]
     TextToImage,
     /// `image_to_image` task.
     #[value(name = "image2image")]
+    #[serde(rename = "image2image")]
     ImageToImage,
     /// `text_to_image` and `image_to_image` tasks.
     #[value(name = "full")]
+    #[serde(rename = "full")]
     Full,
 }
 impl TaskType {
@@ -433,3 +685,89 @@ impl TaskType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_cli(args: &[&str]) -> (Cli, ArgMatches) {
+        let matches = Cli::command()
+            .try_get_matches_from(std::iter::once("sd-api-server").chain(args.iter().copied()))
+            .unwrap();
+        let cli = Cli::from_arg_matches(&matches).unwrap();
+
+        (cli, matches)
+    }
+
+    #[test]
+    fn file_values_replace_the_defaults_only() {
+        let mut value = 1;
+        merge(&mut value, false, Some(2));
+        assert_eq!(value, 2);
+
+        let mut value = 1;
+        merge(&mut value, true, Some(2));
+        assert_eq!(value, 1);
+
+        let mut value = 1;
+        merge(&mut value, false, None);
+        assert_eq!(value, 1);
+    }
+
+    #[test]
+    fn command_line_options_override_the_config_file() {
+        let (mut cli, matches) = parse_cli(&["--model-name", "sd-v1.4", "--max-queue-size", "4"]);
+        let file_config = FileConfig {
+            model_name: Some("flux".to_string()),
+            max_queue_size: Some(32),
+            threads: Some(8),
+            public_routes: Some(vec!["/echo".to_string()]),
+            ..Default::default()
+        };
+
+        cli.merge_config(&matches, file_config);
+
+        assert_eq!(cli.model_name, "sd-v1.4");
+        assert_eq!(cli.max_queue_size, 4);
+        assert_eq!(cli.threads, 8);
+        assert_eq!(cli.public_routes, vec!["/echo".to_string()]);
+        assert_eq!(cli.usage_file, PathBuf::from(usage::DEFAULT_USAGE_FILE));
+    }
+
+    #[test]
+    fn command_line_options_override_the_model_of_the_models_list() {
+        let (cli, matches) = parse_cli(&["--threads", "4", "--vae", "vae.gguf", "--vae-on-cpu"]);
+        let mut model_config = ModelConfig {
+            name: "flux".to_string(),
+            diffusion_model: "flux.gguf".to_string(),
+            vae: "ae.safetensors".to_string(),
+            clip_l: "clip_l.safetensors".to_string(),
+            threads: 8,
+            ..Default::default()
+        };
+
+        cli.merge_model(&matches, &mut model_config);
+
+        assert_eq!(model_config.name, "flux");
+        assert_eq!(model_config.diffusion_model, "flux.gguf");
+        assert_eq!(model_config.vae, "vae.gguf");
+        assert_eq!(model_config.clip_l, "clip_l.safetensors");
+        assert_eq!(model_config.threads, 4);
+        assert!(model_config.vae_on_cpu);
+        assert_eq!(model_config.task, TaskType::Full);
+    }
+
+    #[test]
+    fn a_port_on_the_command_line_wins_over_a_socket_address_in_the_file() {
+        let (mut cli, matches) = parse_cli(&["--port", "10086"]);
+        let file_config = FileConfig {
+            socket_addr: Some(SocketAddr::from(([127, 0, 0, 1], 8080))),
+            ..Default::default()
+        };
+
+        cli.merge_config(&matches, file_config);
+
+        assert_eq!(cli.socket_addr, None);
+        assert_eq!(cli.port, 10086);
+    }
+}
@@ -0,0 +1,412 @@
+use crate::{error, queue, usage::UsageRecord};
+use hyper::{Body, Method, Response, StatusCode};
+use once_cell::sync::Lazy;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs, io,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+/// Routes counted by their own `route` label. The other paths are counted as `other`, so that a
+/// client cannot grow the number of series.
+const ROUTES: [&str; 14] = [
+    "/echo",
+    "/health",
+    "/metrics",
+    "/v1/images/generations",
+    "/v1/images/edits",
+    "/v1/images/variations",
+    "/v1/models",
+    "/v1/loras",
+    "/v1/queue",
+    "/v1/usage",
+    "/v1/files/download",
+    "/v1/files",
+    "/v1/jobs",
+    "/v1/presets",
+];
+
+/// Bucket bounds of the generation latency, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [
+    1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0,
+];
+
+/// Bucket bounds of the upload sizes, in bytes: 1 KiB to 64 MiB.
+const UPLOAD_BUCKETS: [f64; 9] = [
+    1024.0, 8192.0, 65536.0, 262144.0, 1048576.0, 4194304.0, 16777216.0, 33554432.0, 67108864.0,
+];
+
+/// Upper bounds of the sample steps and their `steps` label, so that the label of the generation
+/// latency takes a few values only. Larger values are labelled `>50`.
+const STEP_BUCKETS: [(u64, &str); 4] = [(10, "<=10"), (20, "<=20"), (30, "<=30"), (50, "<=50")];
+
+/// Upper bounds of the pixel count and their `pixels` label. Larger values are labelled
+/// `>1024x1024`.
+const PIXEL_BUCKETS: [(u64, &str); 3] = [
+    (512 * 512, "<=512x512"),
+    (768 * 768, "<=768x768"),
+    (1024 * 1024, "<=1024x1024"),
+];
+
+/// Directory of the uploaded and generated files.
+const ARCHIVES_DIR: &str = "archives";
+
+/// Interval at which the usage of the archives directory is measured.
+const ARCHIVE_SCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+// the metrics collected since the server started
+static METRICS: Lazy<Mutex<Metrics>> = Lazy::new(|| Mutex::new(Metrics::default()));
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// Write the `_bucket`, `_sum` and `_count` samples of the histogram with the given labels.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = match labels.is_empty() {
+            true => "",
+            false => ",",
+        };
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, separator, self.count
+        );
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+/// Labels of a generation: the endpoint, the model, the pixel bucket and the step bucket.
+type GenerationLabels = (String, String, &'static str, &'static str);
+
+#[derive(Debug)]
+struct Metrics {
+    /// Requests by route, method and status code.
+    requests: BTreeMap<(&'static str, String, u16), u64>,
+    /// Generation latencies by endpoint, model, pixel bucket and step bucket.
+    generations: BTreeMap<GenerationLabels, Histogram>,
+    /// Images produced by endpoint and model.
+    images: BTreeMap<(String, String), u64>,
+    /// Sizes of the files uploaded with `POST /v1/files` or with the image requests.
+    uploads: Histogram,
+    /// Number of files and bytes under the archives directory, as of the last scan.
+    archives: (u64, u64),
+}
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            requests: BTreeMap::new(),
+            generations: BTreeMap::new(),
+            images: BTreeMap::new(),
+            uploads: Histogram::new(&UPLOAD_BUCKETS),
+            archives: (0, 0),
+        }
+    }
+}
+
+fn metrics() -> MutexGuard<'static, Metrics> {
+    match METRICS.lock() {
+        Ok(metrics) => metrics,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Return the `route` label of the path: the first of the [`ROUTES`] the path is under, or
+/// `other`.
+pub(crate) fn route_label(path: &str) -> &'static str {
+    let path = path.trim_end_matches('/');
+
+    ROUTES
+        .iter()
+        .find(|route| {
+            path == **route
+                || path
+                    .strip_prefix(**route)
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+        .copied()
+        .unwrap_or("other")
+}
+
+/// Count a request answered with the status code. Extension methods are counted as `OTHER`.
+pub(crate) fn count_request(route: &'static str, method: &Method, status: StatusCode) {
+    let method = match *method {
+        Method::GET
+        | Method::POST
+        | Method::PUT
+        | Method::DELETE
+        | Method::HEAD
+        | Method::OPTIONS
+        | Method::PATCH => method.to_string(),
+        _ => "OTHER".to_string(),
+    };
+
+    *metrics()
+        .requests
+        .entry((route, method, status.as_u16()))
+        .or_default() += 1;
+}
+
+/// Count a completed image request: its latency and the images produced.
+pub(crate) fn observe_generation(record: &UsageRecord) {
+    let mut metrics = metrics();
+
+    metrics
+        .generations
+        .entry((
+            record.endpoint.clone(),
+            record.model.clone(),
+            pixel_bucket(record.width * record.height),
+            step_bucket(record.steps),
+        ))
+        .or_insert_with(|| Histogram::new(&LATENCY_BUCKETS))
+        .observe(record.duration_ms as f64 / 1000.0);
+
+    *metrics
+        .images
+        .entry((record.endpoint.clone(), record.model.clone()))
+        .or_default() += record.images;
+}
+
+/// Return the `pixels` label of the pixel count, e.g. `<=512x512`.
+fn pixel_bucket(pixels: u64) -> &'static str {
+    PIXEL_BUCKETS
+        .iter()
+        .find(|(bound, _)| pixels <= *bound)
+        .map(|(_, label)| *label)
+        .unwrap_or(">1024x1024")
+}
+
+/// Return the `steps` label of the sample steps, e.g. `<=20`, or an empty label without steps.
+fn step_bucket(steps: Option<u64>) -> &'static str {
+    match steps {
+        Some(steps) => STEP_BUCKETS
+            .iter()
+            .find(|(bound, _)| steps <= *bound)
+            .map(|(_, label)| *label)
+            .unwrap_or(">50"),
+        None => "",
+    }
+}
+
+/// Count a file uploaded with `POST /v1/files`, or stored from a file field of an image request.
+pub(crate) fn observe_upload(bytes: u64) {
+    metrics().uploads.observe(bytes as f64);
+}
+
+/// Escape a label value of the text exposition format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Measure the archives directory when the server starts and then at every interval, so that the
+/// scrapes read the last measure instead of walking the directory.
+pub(crate) fn spawn_archive_scan() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(ARCHIVE_SCAN_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            match directory_usage(Path::new(ARCHIVES_DIR)).await {
+                Ok(usage) => metrics().archives = usage,
+                Err(e) => {
+                    // log
+                    warn!(target: "stdout", "Failed to measure the `{}` directory. {}", ARCHIVES_DIR, e);
+                }
+            }
+        }
+    });
+}
+
+/// Return the number of files and their total size under the directory, yielding to the other
+/// tasks after each directory, as the runtime has a single thread. A missing directory is empty.
+async fn directory_usage(path: &Path) -> io::Result<(u64, u64)> {
+    let mut files = 0;
+    let mut bytes = 0;
+    let mut dirs = vec![path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                files += 1;
+                bytes += metadata.len();
+            }
+        }
+
+        tokio::task::yield_now().await;
+    }
+
+    Ok((files, bytes))
+}
+
+/// Render the metrics in the Prometheus text exposition format.
+fn render() -> String {
+    let mut out = String::new();
+
+    {
+        let metrics = metrics();
+
+        out.push_str("# HELP sd_http_requests_total Number of HTTP requests by route, method and status code.\n");
+        out.push_str("# TYPE sd_http_requests_total counter\n");
+        for ((route, method, status), count) in metrics.requests.iter() {
+            let _ = writeln!(
+                out,
+                "sd_http_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}",
+                route,
+                escape(method),
+                status,
+                count
+            );
+        }
+
+        out.push_str("# HELP sd_generation_duration_seconds Duration of the completed image requests, excluding the queue wait.\n");
+        out.push_str("# TYPE sd_generation_duration_seconds histogram\n");
+        for ((endpoint, model, pixels, steps), histogram) in metrics.generations.iter() {
+            let labels = format!(
+                "endpoint=\"{}\",model=\"{}\",pixels=\"{}\",steps=\"{}\"",
+                escape(endpoint),
+                escape(model),
+                pixels,
+                steps
+            );
+            histogram.render(&mut out, "sd_generation_duration_seconds", &labels);
+        }
+
+        out.push_str(
+            "# HELP sd_images_generated_total Number of images produced by endpoint and model.\n",
+        );
+        out.push_str("# TYPE sd_images_generated_total counter\n");
+        for ((endpoint, model), count) in metrics.images.iter() {
+            let _ = writeln!(
+                out,
+                "sd_images_generated_total{{endpoint=\"{}\",model=\"{}\"}} {}",
+                escape(endpoint),
+                escape(model),
+                count
+            );
+        }
+
+        out.push_str(
+            "# HELP sd_upload_size_bytes Size of the files uploaded with POST /v1/files or with the image requests.\n",
+        );
+        out.push_str("# TYPE sd_upload_size_bytes histogram\n");
+        metrics.uploads.render(&mut out, "sd_upload_size_bytes", "");
+
+        let (files, bytes) = metrics.archives;
+        out.push_str("# HELP sd_archive_files Number of files stored under archives/.\n");
+        out.push_str("# TYPE sd_archive_files gauge\n");
+        let _ = writeln!(out, "sd_archive_files {}", files);
+        out.push_str("# HELP sd_archive_bytes Bytes stored under archives/.\n");
+        out.push_str("# TYPE sd_archive_bytes gauge\n");
+        let _ = writeln!(out, "sd_archive_bytes {}", bytes);
+    }
+
+    let status = queue::status();
+    out.push_str(
+        "# HELP sd_queue_size Number of image requests waiting in the generation queue.\n",
+    );
+    out.push_str("# TYPE sd_queue_size gauge\n");
+    let _ = writeln!(out, "sd_queue_size {}", status.size);
+    out.push_str(
+        "# HELP sd_queue_max_size Maximum number of image requests waiting in the generation queue.\n",
+    );
+    out.push_str("# TYPE sd_queue_max_size gauge\n");
+    let _ = writeln!(out, "sd_queue_max_size {}", status.max_size);
+    out.push_str("# HELP sd_queue_running Whether an image request is being run.\n");
+    out.push_str("# TYPE sd_queue_running gauge\n");
+    let _ = writeln!(out, "sd_queue_running {}", u8::from(status.running));
+
+    out
+}
+
+/// Expose the metrics to Prometheus.
+///
+/// - `GET /metrics`: Return the metrics in the text exposition format.
+///
+pub(crate) fn metrics_handler(method: &Method) -> Response<Body> {
+    if method != Method::GET {
+        return error::method_not_allowed(method);
+    }
+
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(Body::from(render()));
+
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let err_msg = e.to_string();
+
+            // log
+            error!(target: "stdout", "{}", &err_msg);
+
+            error::internal_server_error(err_msg)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generation_labels_are_bucketed() {
+        assert_eq!(pixel_bucket(512 * 512), "<=512x512");
+        assert_eq!(pixel_bucket(512 * 768), "<=768x768");
+        assert_eq!(pixel_bucket(1024 * 1024), "<=1024x1024");
+        assert_eq!(pixel_bucket(2048 * 2048), ">1024x1024");
+
+        assert_eq!(step_bucket(Some(1)), "<=10");
+        assert_eq!(step_bucket(Some(25)), "<=30");
+        assert_eq!(step_bucket(Some(150)), ">50");
+        assert_eq!(step_bucket(None), "");
+    }
+}
@@ -0,0 +1,352 @@
+use crate::{error::ServerError, loras, utils::unix_timestamp, TaskType};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+// the model served by the server
+static MODEL: OnceCell<RegisteredModel> = OnceCell::new();
+
+/// Configuration of a model served by the server.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ModelConfig {
+    /// Name of the model, listed by the models endpoint.
+    pub(crate) name: String,
+    /// Path to the full model. Empty if the model is a standalone diffusion model.
+    pub(crate) model: String,
+    /// Path to the standalone diffusion model. Empty if the model is a full model.
+    pub(crate) diffusion_model: String,
+    /// Path to the vae.
+    pub(crate) vae: String,
+    /// Path to the clip-l text encoder.
+    pub(crate) clip_l: String,
+    /// Path to the t5xxl text encoder.
+    pub(crate) t5xxl: String,
+    /// Path to the lora model directory.
+    pub(crate) lora_model_dir: Option<String>,
+    /// Path to the control net model.
+    pub(crate) control_net: Option<String>,
+    /// Keep the control net on cpu.
+    pub(crate) control_net_cpu: bool,
+    /// Keep clip on cpu.
+    pub(crate) clip_on_cpu: bool,
+    /// Keep vae on cpu.
+    pub(crate) vae_on_cpu: bool,
+    /// Number of threads to use during computation.
+    pub(crate) threads: i32,
+    /// Task type.
+    pub(crate) task: TaskType,
+    /// Default generation parameters, applied to the fields a request leaves unset.
+    pub(crate) defaults: GenerationDefaults,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            model: String::new(),
+            diffusion_model: String::new(),
+            vae: String::new(),
+            clip_l: String::new(),
+            t5xxl: String::new(),
+            lora_model_dir: None,
+            control_net: None,
+            control_net_cpu: false,
+            clip_on_cpu: false,
+            vae_on_cpu: false,
+            threads: -1,
+            task: TaskType::Full,
+            defaults: GenerationDefaults::default(),
+        }
+    }
+}
+
+/// Default generation parameters of a model.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct GenerationDefaults {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) negative_prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) n: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) cfg_scale: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) sample_method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) steps: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) height: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) width: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) control_strength: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) seed: Option<i32>,
+    /// Strength of the edit, applied to image edits only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) strength: Option<f32>,
+}
+impl GenerationDefaults {
+    /// Return the parameters that are set, as JSON values keyed by the request field names.
+    pub(crate) fn to_fields(&self) -> serde_json::Map<String, serde_json::Value> {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(map)) => map,
+            _ => serde_json::Map::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RegisteredModel {
+    config: ModelConfig,
+    /// The Unix timestamp (in seconds) for when the model was initialized.
+    created: u64,
+}
+impl RegisteredModel {
+    fn info(&self) -> ModelInfo {
+        let config = &self.config;
+
+        let path = |path: &str| match path.is_empty() {
+            true => None,
+            false => Some(path.to_string()),
+        };
+
+        ModelInfo {
+            id: config.name.clone(),
+            object: "model".to_string(),
+            created: self.created,
+            owned_by: "sd-api-server".to_string(),
+            task: config.task,
+            files: ModelFiles {
+                model: path(&config.model),
+                diffusion_model: path(&config.diffusion_model),
+                vae: path(&config.vae),
+                clip_l: path(&config.clip_l),
+                t5xxl: path(&config.t5xxl),
+                control_net: config.control_net.clone(),
+            },
+            control_net: config.control_net.is_some(),
+            loras: match &config.lora_model_dir {
+                Some(lora_model_dir) => loras::lora_names(lora_model_dir),
+                None => Vec::new(),
+            },
+            defaults: config.defaults.clone(),
+        }
+    }
+}
+
+/// Description of a model served by the server.
+#[derive(Debug, Serialize)]
+pub(crate) struct ModelInfo {
+    /// The model name.
+    pub(crate) id: String,
+    /// The object type, which is always `model`.
+    pub(crate) object: String,
+    /// The Unix timestamp (in seconds) for when the model was initialized.
+    pub(crate) created: u64,
+    /// The owner of the model.
+    pub(crate) owned_by: String,
+    /// The tasks enabled for the model.
+    pub(crate) task: TaskType,
+    /// The component files of the model.
+    pub(crate) files: ModelFiles,
+    /// Whether a control net is attached to the model.
+    pub(crate) control_net: bool,
+    /// The names of the LoRA models available in the lora model directory.
+    pub(crate) loras: Vec<String>,
+    /// The default generation parameters of the model.
+    pub(crate) defaults: GenerationDefaults,
+}
+
+/// Component files of a model. A full model has `model`, while a standalone diffusion model has
+/// `diffusion_model` and its text encoders and vae.
+#[derive(Debug, Serialize)]
+pub(crate) struct ModelFiles {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) diffusion_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) vae: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) clip_l: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) t5xxl: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) control_net: Option<String>,
+}
+
+/// Initialize the stable diffusion context of the model and register the model.
+///
+/// `llama-core` keeps a single stable diffusion context per process, so only one model can be
+/// initialized. Several models are served by running one server per model.
+pub(crate) fn init_model(config: ModelConfig) -> Result<(), ServerError> {
+    if config.name.is_empty() {
+        return Err(ServerError::ArgumentError(
+            "The name of the model should not be empty.".into(),
+        ));
+    }
+
+    if let Some(served) = MODEL.get() {
+        return Err(ServerError::Operation(format!(
+            "Failed to initialize the model `{}`. The model `{}` is already initialized, and the backend supports a single stable diffusion context per process.",
+            config.name, served.config.name
+        )));
+    }
+
+    // log model name
+    info!(target: "stdout", "model_name: {}", &config.name);
+
+    // log task type
+    info!(target: "stdout", "task: {:?}", &config.task);
+
+    // log lora model directory
+    if let Some(lora_model_dir) = &config.lora_model_dir {
+        info!(target: "stdout", "lora_model_dir: {}", lora_model_dir);
+    } else {
+        info!(target: "stdout", "lora_model_dir: None");
+    }
+
+    // log control net
+    if let Some(control_net) = &config.control_net {
+        info!(target: "stdout", "control_net: {}", &control_net);
+    }
+
+    // log control net on cpu
+    info!(target: "stdout", "control_net_cpu: {}", config.control_net_cpu);
+
+    // log threads
+    info!(target: "stdout", "threads: {}", config.threads);
+
+    // log clip on cpu
+    info!(target: "stdout", "clip_on_cpu: {}", config.clip_on_cpu);
+
+    // log vae on cpu
+    info!(target: "stdout", "vae_on_cpu: {}", config.vae_on_cpu);
+
+    // Determine which model option is set
+    if !config.model.is_empty() {
+        info!(target: "stdout", "model: {}", &config.model);
+
+        // initialize the stable diffusion context
+        llama_core::init_sd_context_with_full_model(
+            &config.model,
+            config.lora_model_dir.as_deref(),
+            config.control_net.as_deref(),
+            config.control_net_cpu,
+            config.clip_on_cpu,
+            config.vae_on_cpu,
+            config.threads,
+            config.task.to_sd_context_type(),
+        )
+        .map_err(|e| ServerError::Operation(format!("{}", e)))?;
+    } else if !config.diffusion_model.is_empty() {
+        // if diffusion_model is not empty, check if diffusion_model is a valid path
+        if !PathBuf::from(&config.diffusion_model).exists() {
+            return Err(ServerError::ArgumentError(
+                "The path to the diffusion model does not exist.".into(),
+            ));
+        }
+        info!(target: "stdout", "diffusion model: {}", &config.diffusion_model);
+
+        // if vae is not empty, check if vae is a valid path
+        if !config.vae.is_empty() && !PathBuf::from(&config.vae).exists() {
+            return Err(ServerError::ArgumentError(
+                "The path to the vae does not exist.".into(),
+            ));
+        }
+        info!(target: "stdout", "vae: {}", &config.vae);
+
+        // if clip_l is not empty, check if clip_l is a valid path
+        if !config.clip_l.is_empty() && !PathBuf::from(&config.clip_l).exists() {
+            return Err(ServerError::ArgumentError(
+                "The path to the clip-l text encoder does not exist.".into(),
+            ));
+        }
+        info!(target: "stdout", "clip_l: {}", &config.clip_l);
+
+        // if t5xxl is not empty, check if t5xxl is a valid path
+        if !config.t5xxl.is_empty() && !PathBuf::from(&config.t5xxl).exists() {
+            return Err(ServerError::ArgumentError(
+                "The path to the t5xxl text encoder does not exist.".into(),
+            ));
+        }
+        info!(target: "stdout", "t5xxl: {}", &config.t5xxl);
+
+        // initialize the stable diffusion context
+        llama_core::init_sd_context_with_standalone_model(
+            &config.diffusion_model,
+            &config.vae,
+            &config.clip_l,
+            &config.t5xxl,
+            config.lora_model_dir.as_deref(),
+            config.control_net.as_deref(),
+            config.control_net_cpu,
+            config.clip_on_cpu,
+            config.vae_on_cpu,
+            config.threads,
+            config.task.to_sd_context_type(),
+        )
+        .map_err(|e| ServerError::Operation(format!("{}", e)))?;
+    } else {
+        return Err(ServerError::ArgumentError(
+            "The '--model' or '--diffusion-model' option should be specified.".into(),
+        ));
+    }
+
+    let name = config.name.clone();
+    let registered = RegisteredModel {
+        config,
+        created: unix_timestamp(),
+    };
+    if MODEL.set(registered).is_err() {
+        return Err(ServerError::Operation(format!(
+            "Failed to register the model `{}`.",
+            name
+        )));
+    }
+
+    Ok(())
+}
+
+/// Return the name of the served model. The `model` field of the requests does not select the
+/// model, since the backend serves a single model per process, but a name other than this one is
+/// rejected.
+pub(crate) fn model_name() -> Option<String> {
+    MODEL.get().map(|model| model.config.name.clone())
+}
+
+/// Describe the served model.
+pub(crate) fn list_models() -> Vec<ModelInfo> {
+    MODEL.get().map(RegisteredModel::info).into_iter().collect()
+}
+
+/// Describe the served model if it has the given name.
+pub(crate) fn retrieve_model(name: &str) -> Option<ModelInfo> {
+    MODEL
+        .get()
+        .filter(|model| model.config.name == name)
+        .map(RegisteredModel::info)
+}
+
+/// Return the default generation parameters of the served model.
+pub(crate) fn generation_defaults() -> Option<GenerationDefaults> {
+    MODEL.get().map(|model| model.config.defaults.clone())
+}
+
+/// Return whether the served model is a standalone diffusion model, e.g. Flux or SD3, rather than
+/// a full model.
+pub(crate) fn is_diffusion_model() -> bool {
+    MODEL
+        .get()
+        .map(|model| model.config.model.is_empty())
+        .unwrap_or(false)
+}
+
+/// Return the lora model directory of the served model.
+pub(crate) fn lora_model_dir() -> Option<String> {
+    MODEL
+        .get()
+        .and_then(|model| model.config.lora_model_dir.clone())
+}
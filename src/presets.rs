@@ -0,0 +1,239 @@
+use crate::{error::ServerError, models::GenerationDefaults};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard},
+};
+use thiserror::Error;
+
+// named generation presets, keyed by name
+static PRESETS: Lazy<Mutex<BTreeMap<String, GenerationDefaults>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+// file the presets are loaded from and saved to
+static PRESETS_FILE: OnceCell<PathBuf> = OnceCell::new();
+
+/// A named preset, as returned by the presets endpoints.
+#[derive(Debug, Serialize)]
+pub(crate) struct PresetInfo {
+    /// The name of the preset, referenced by the `preset` field of the image requests.
+    pub(crate) id: String,
+    /// The object type, which is always `preset`.
+    pub(crate) object: String,
+    /// The generation parameters of the preset.
+    #[serde(flatten)]
+    pub(crate) parameters: GenerationDefaults,
+}
+impl PresetInfo {
+    fn new(name: &str, parameters: GenerationDefaults) -> Self {
+        Self {
+            id: name.to_string(),
+            object: "preset".to_string(),
+            parameters,
+        }
+    }
+}
+
+/// Error returned while changing the presets.
+#[derive(Error, Debug)]
+pub(crate) enum PresetError {
+    /// The preset name is not valid.
+    #[error("Invalid preset name `{0}`. The name should only contain ASCII letters, digits, `-`, `_` and `.`.")]
+    InvalidName(String),
+    /// No preset has the name.
+    #[error("The preset `{0}` is not found.")]
+    NotFound(String),
+    /// The presets file cannot be written.
+    #[error("Failed to save the presets. {0}")]
+    Save(String),
+}
+
+fn presets() -> MutexGuard<'static, BTreeMap<String, GenerationDefaults>> {
+    match PRESETS.lock() {
+        Ok(presets) => presets,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Load the presets from the JSON file, which maps the preset names to their parameters. The
+/// presets changed through the presets endpoints are saved to the same file. A missing file is
+/// created on the first change.
+pub(crate) fn init_presets(path: PathBuf) -> Result<(), ServerError> {
+    let loaded: BTreeMap<String, GenerationDefaults> = match path.exists() {
+        true => {
+            let text = fs::read_to_string(&path).map_err(|e| {
+                ServerError::ArgumentError(format!(
+                    "Failed to read the presets file {}. {}",
+                    path.display(),
+                    e
+                ))
+            })?;
+
+            serde_json::from_str(&text).map_err(|e| {
+                ServerError::ArgumentError(format!(
+                    "Failed to parse the presets file {}. {}",
+                    path.display(),
+                    e
+                ))
+            })?
+        }
+        false => {
+            // log
+            info!(target: "stdout", "The presets file {} does not exist, and will be created on the first change.", path.display());
+
+            BTreeMap::new()
+        }
+    };
+
+    if let Some(name) = loaded.keys().find(|name| !is_valid_name(name)) {
+        return Err(ServerError::ArgumentError(format!(
+            "Invalid presets file {}. {}",
+            path.display(),
+            PresetError::InvalidName(name.clone())
+        )));
+    }
+
+    // log
+    info!(target: "stdout", "presets: {}", loaded.keys().cloned().collect::<Vec<_>>().join(", "));
+
+    *presets() = loaded;
+
+    if PRESETS_FILE.set(path).is_err() {
+        return Err(ServerError::Operation(
+            "Failed to set PRESETS_FILE.".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Write the presets to the presets file, if any. The file is replaced atomically.
+fn save(presets: &BTreeMap<String, GenerationDefaults>) -> Result<(), PresetError> {
+    let path = match PRESETS_FILE.get() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    let s = serde_json::to_string_pretty(presets).map_err(|e| PresetError::Save(e.to_string()))?;
+
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, s).map_err(|e| PresetError::Save(e.to_string()))?;
+    fs::rename(&tmp_path, path).map_err(|e| PresetError::Save(e.to_string()))
+}
+
+/// Return the sorted names of the presets.
+pub(crate) fn preset_names() -> Vec<String> {
+    presets().keys().cloned().collect()
+}
+
+/// Return the parameters of the preset with the given name.
+pub(crate) fn preset(name: &str) -> Option<GenerationDefaults> {
+    presets().get(name).cloned()
+}
+
+/// Describe the presets, sorted by name.
+pub(crate) fn list_presets() -> Vec<PresetInfo> {
+    presets()
+        .iter()
+        .map(|(name, parameters)| PresetInfo::new(name, parameters.clone()))
+        .collect()
+}
+
+/// Describe the preset with the given name.
+pub(crate) fn retrieve_preset(name: &str) -> Option<PresetInfo> {
+    preset(name).map(|parameters| PresetInfo::new(name, parameters))
+}
+
+/// Create or replace the preset. Returns the preset and whether it was created.
+pub(crate) fn save_preset(
+    name: &str,
+    parameters: GenerationDefaults,
+) -> Result<(PresetInfo, bool), PresetError> {
+    if !is_valid_name(name) {
+        return Err(PresetError::InvalidName(name.to_string()));
+    }
+
+    let mut presets = presets();
+
+    let mut updated = presets.clone();
+    let created = updated
+        .insert(name.to_string(), parameters.clone())
+        .is_none();
+    save(&updated)?;
+    *presets = updated;
+
+    Ok((PresetInfo::new(name, parameters), created))
+}
+
+/// Delete the preset with the given name.
+pub(crate) fn delete_preset(name: &str) -> Result<(), PresetError> {
+    let mut presets = presets();
+
+    if !presets.contains_key(name) {
+        return Err(PresetError::NotFound(name.to_string()));
+    }
+
+    let mut updated = presets.clone();
+    updated.remove(name);
+    save(&updated)?;
+    *presets = updated;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preset_names_are_checked() {
+        assert!(is_valid_name("flux-fast_v1.2"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("../presets"));
+        assert!(!is_valid_name("fast preset"));
+        assert!(matches!(
+            save_preset("fast preset", GenerationDefaults::default()),
+            Err(PresetError::InvalidName(_))
+        ));
+    }
+
+    #[test]
+    fn presets_are_created_replaced_and_deleted() {
+        let name = "presets-test-fast";
+        let parameters = GenerationDefaults {
+            steps: Some(4),
+            ..Default::default()
+        };
+
+        let (info, created) = save_preset(name, parameters).unwrap();
+        assert_eq!(info.id, name);
+        assert_eq!(info.object, "preset");
+        assert!(created);
+
+        let parameters = GenerationDefaults {
+            steps: Some(8),
+            cfg_scale: Some(1.0),
+            ..Default::default()
+        };
+        let (_, created) = save_preset(name, parameters).unwrap();
+        assert!(!created);
+        assert!(preset_names().contains(&name.to_string()));
+        let parameters = retrieve_preset(name).unwrap().parameters;
+        assert_eq!(parameters.steps, Some(8));
+        assert_eq!(parameters.cfg_scale, Some(1.0));
+
+        delete_preset(name).unwrap();
+        assert!(preset(name).is_none());
+        assert!(matches!(delete_preset(name), Err(PresetError::NotFound(_))));
+    }
+}
@@ -0,0 +1,271 @@
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+
+/// Default maximum number of requests waiting in the generation queue.
+pub(crate) const DEFAULT_MAX_QUEUE_SIZE: usize = 16;
+
+/// Number of recent generations used to estimate the waiting time.
+const DURATION_WINDOW: usize = 10;
+
+// generation queue in front of the stable diffusion context
+pub(crate) static GENERATION_QUEUE: OnceCell<GenerationQueue> = OnceCell::new();
+
+/// A bounded FIFO queue serializing the requests sent to the stable diffusion context.
+#[derive(Debug)]
+pub(crate) struct GenerationQueue {
+    max_size: usize,
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    next_ticket: u64,
+    waiting: VecDeque<u64>,
+    running: Option<(u64, Instant)>,
+    durations: VecDeque<Duration>,
+}
+impl QueueState {
+    fn average_duration(&self) -> Option<Duration> {
+        if self.durations.is_empty() {
+            return None;
+        }
+
+        let total: Duration = self.durations.iter().sum();
+        Some(total / self.durations.len() as u32)
+    }
+
+    /// Estimate the seconds to wait for a request with `ahead` requests waiting before it.
+    fn estimated_wait(&self, ahead: usize) -> Option<u64> {
+        let average = self.average_duration()?;
+
+        let mut wait = average * ahead as u32;
+        if let Some((_, started_at)) = self.running {
+            wait += average.saturating_sub(started_at.elapsed());
+        }
+
+        Some(wait.as_secs())
+    }
+}
+
+/// Snapshot of the generation queue.
+#[derive(Debug, Serialize)]
+pub(crate) struct QueueStatus {
+    /// The object type, which is always `queue`.
+    pub(crate) object: String,
+    /// Number of requests waiting in the queue.
+    pub(crate) size: usize,
+    /// Maximum number of requests waiting in the queue.
+    pub(crate) max_size: usize,
+    /// Whether a request is being run.
+    pub(crate) running: bool,
+    /// Estimated seconds a new request waits before it runs. Absent if no request finished yet.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) estimated_wait: Option<u64>,
+}
+
+/// Error returned if a request cannot enter the queue.
+#[derive(Debug)]
+pub(crate) enum QueueError {
+    /// The queue is full.
+    Full {
+        /// Maximum number of requests waiting in the queue.
+        max_size: usize,
+        /// Estimated seconds before a slot is free.
+        estimated_wait: Option<u64>,
+    },
+}
+
+impl GenerationQueue {
+    pub(crate) fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            state: Mutex::new(QueueState::default()),
+            notify: Notify::new(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, QueueState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Add a request to the end of the queue, returning its ticket id.
+    fn push(&self) -> Result<u64, QueueError> {
+        let mut state = self.state();
+
+        if state.waiting.len() >= self.max_size {
+            let ahead = state.waiting.len();
+            return Err(QueueError::Full {
+                max_size: self.max_size,
+                estimated_wait: state.estimated_wait(ahead),
+            });
+        }
+
+        let id = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.push_back(id);
+
+        Ok(id)
+    }
+
+    fn position(&self, id: u64) -> Option<(usize, Option<u64>)> {
+        let state = self.state();
+
+        let ahead = state.waiting.iter().position(|ticket| *ticket == id)?;
+
+        Some((ahead + 1, state.estimated_wait(ahead)))
+    }
+
+    fn release(&self, id: u64) {
+        {
+            let mut state = self.state();
+
+            match state.running {
+                Some((running_id, started_at)) if running_id == id => {
+                    state.running = None;
+
+                    state.durations.push_back(started_at.elapsed());
+                    if state.durations.len() > DURATION_WINDOW {
+                        state.durations.pop_front();
+                    }
+                }
+                _ => state.waiting.retain(|ticket| *ticket != id),
+            }
+        }
+
+        self.notify.notify_waiters();
+    }
+
+    fn try_start(&self, id: u64) -> bool {
+        let mut state = self.state();
+
+        if state.running.is_none() && state.waiting.front() == Some(&id) {
+            state.waiting.pop_front();
+            state.running = Some((id, Instant::now()));
+
+            return true;
+        }
+
+        false
+    }
+}
+
+fn generation_queue() -> &'static GenerationQueue {
+    GENERATION_QUEUE.get_or_init(|| GenerationQueue::new(DEFAULT_MAX_QUEUE_SIZE))
+}
+
+/// Add a request to the end of the generation queue.
+pub(crate) fn enqueue() -> Result<Ticket, QueueError> {
+    generation_queue().push().map(|id| Ticket { id })
+}
+
+/// Return the current status of the generation queue.
+pub(crate) fn status() -> QueueStatus {
+    let queue = generation_queue();
+    let state = queue.state();
+
+    QueueStatus {
+        object: "queue".to_string(),
+        size: state.waiting.len(),
+        max_size: queue.max_size,
+        running: state.running.is_some(),
+        estimated_wait: state.estimated_wait(state.waiting.len()),
+    }
+}
+
+/// Return the 1-based position of the ticket in the queue and the estimated seconds to wait.
+///
+/// Returns `None` if the ticket is not waiting anymore.
+pub(crate) fn position(id: u64) -> Option<(usize, Option<u64>)> {
+    generation_queue().position(id)
+}
+
+/// A place in the generation queue. The place is released when the ticket is dropped.
+#[derive(Debug)]
+pub(crate) struct Ticket {
+    id: u64,
+}
+impl Ticket {
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Wait until all the requests ahead of this ticket finished.
+    pub(crate) async fn wait_turn(&self) {
+        let queue = generation_queue();
+
+        loop {
+            // register for the notification before checking, so no release is missed
+            let notified = queue.notify.notified();
+
+            if queue.try_start(self.id) {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+}
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        generation_queue().release(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_start_in_arrival_order() {
+        let queue = GenerationQueue::new(4);
+        let first = queue.push().unwrap();
+        let second = queue.push().unwrap();
+        assert_eq!(queue.position(first), Some((1, None)));
+        assert_eq!(queue.position(second), Some((2, None)));
+
+        assert!(!queue.try_start(second));
+        assert!(queue.try_start(first));
+        assert_eq!(queue.position(first), None);
+        assert_eq!(queue.position(second), Some((1, None)));
+
+        // a single request runs at a time
+        assert!(!queue.try_start(second));
+        queue.release(first);
+        assert!(queue.try_start(second));
+
+        // the finished request estimates the wait of the next ones
+        let third = queue.push().unwrap();
+        assert!(matches!(queue.position(third), Some((1, Some(_)))));
+    }
+
+    #[test]
+    fn the_queue_rejects_requests_beyond_its_capacity() {
+        let queue = GenerationQueue::new(2);
+        let first = queue.push().unwrap();
+        let second = queue.push().unwrap();
+        assert!(matches!(
+            queue.push(),
+            Err(QueueError::Full { max_size: 2, .. })
+        ));
+
+        // the running request leaves a slot
+        assert!(queue.try_start(first));
+        let third = queue.push().unwrap();
+
+        // so does a request leaving the queue before its turn
+        queue.release(second);
+        queue.push().unwrap();
+        assert_eq!(queue.position(third), Some((1, None)));
+        assert!(queue.push().is_err());
+    }
+}
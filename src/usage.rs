@@ -0,0 +1,448 @@
+use crate::{error::ServerError, limits};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Default path of the usage file.
+pub(crate) const DEFAULT_USAGE_FILE: &str = "usage.jsonl";
+
+/// Seconds in a day.
+const DAY: u64 = 86400;
+
+// file the usage records are appended to
+static USAGE_FILE: OnceCell<PathBuf> = OnceCell::new();
+
+// the usage of the records read from the usage file so far
+static RECORDS: Lazy<Mutex<RecordCache>> = Lazy::new(|| Mutex::new(RecordCache::default()));
+
+/// The records of the usage file, read incrementally: a report only reads the lines appended
+/// since the previous report.
+///
+/// The records are not kept: they are added to the usage of their UTC day, API key and model, so
+/// that the memory grows with the days of the file rather than with its requests. The price is
+/// that a report resolves its period to whole days.
+#[derive(Debug, Default)]
+struct RecordCache {
+    /// Number of bytes of the file read so far, up to the last complete line.
+    offset: u64,
+    /// Number of lines read so far.
+    lines: usize,
+    /// The usage of each day since the epoch, API key and model.
+    days: BTreeMap<(u64, Option<String>, String), UsageSummary>,
+}
+impl RecordCache {
+    /// Read the lines appended to the file since the last call. A file shorter than the bytes read
+    /// so far was replaced, and is read again from the start.
+    fn refresh(&mut self, path: &Path) -> std::io::Result<()> {
+        let len = match fs::metadata(path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        if len < self.offset {
+            *self = RecordCache::default();
+        }
+        if len == self.offset {
+            return Ok(());
+        }
+
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut bytes = Vec::new();
+        file.take(len - self.offset).read_to_end(&mut bytes)?;
+
+        // leave a line being written for the next call
+        let complete = match bytes.iter().rposition(|byte| *byte == b'\n') {
+            Some(i) => i + 1,
+            None => return Ok(()),
+        };
+        self.offset += complete as u64;
+
+        for line in String::from_utf8_lossy(&bytes[..complete]).lines() {
+            self.lines += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str(line) {
+                Ok(record) => self.add(&record),
+                Err(e) => {
+                    // log
+                    warn!(target: "stdout", "Skip the invalid line {} of the usage file. {}", self.lines, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add(&mut self, record: &UsageRecord) {
+        self.days
+            .entry((
+                record.created / DAY,
+                record.key.clone(),
+                record.model.clone(),
+            ))
+            .or_default()
+            .add(record);
+    }
+
+    /// Aggregate the usage of the days in `[from_day, to_day)` by key and model.
+    fn report(&self, from_day: Option<u64>, to_day: Option<u64>, key: Option<&str>) -> UsageReport {
+        let mut groups: BTreeMap<(Option<String>, String), UsageSummary> = BTreeMap::new();
+        let mut total = UsageSummary::default();
+        for ((day, day_key, model), usage) in self.days.iter() {
+            if from_day.is_some_and(|from_day| *day < from_day)
+                || to_day.is_some_and(|to_day| *day >= to_day)
+                || key.is_some_and(|key| day_key.as_deref() != Some(key))
+            {
+                continue;
+            }
+
+            groups
+                .entry((day_key.clone(), model.clone()))
+                .or_insert_with(|| UsageSummary {
+                    key: Some(day_key.clone()),
+                    model: Some(model.clone()),
+                    ..Default::default()
+                })
+                .merge(usage);
+            total.merge(usage);
+        }
+
+        UsageReport {
+            object: "usage".to_string(),
+            from: from_day.map(|from_day| from_day * DAY),
+            to: to_day.map(|to_day| to_day * DAY),
+            data: groups.into_values().collect(),
+            total,
+        }
+    }
+}
+
+fn records() -> MutexGuard<'static, RecordCache> {
+    match RECORDS.lock() {
+        Ok(records) => records,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// A completed image request, stored as one line of the usage file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct UsageRecord {
+    /// Unix timestamp (in seconds) of the completion of the request.
+    pub(crate) created: u64,
+    /// The name of the API key of the request, if any.
+    pub(crate) key: Option<String>,
+    /// The endpoint: `images.generations`, `images.edits` or `images.variations`.
+    pub(crate) endpoint: String,
+    /// The model generating the images.
+    pub(crate) model: String,
+    /// Number of images generated.
+    pub(crate) images: u64,
+    /// Height of the images.
+    pub(crate) height: u64,
+    /// Width of the images.
+    pub(crate) width: u64,
+    /// Number of sample steps. Absent for the variations, which do not set them.
+    pub(crate) steps: Option<u64>,
+    /// The sampling method, if set by the request.
+    pub(crate) sample_method: Option<String>,
+    /// Wall-clock duration of the generation in milliseconds, excluding the queue wait.
+    pub(crate) duration_ms: u64,
+}
+impl UsageRecord {
+    /// Start a record of a request whose generation started at the given instant and just
+    /// finished.
+    pub(crate) fn finished(started: Instant) -> Self {
+        Self {
+            created: unix_time(),
+            duration_ms: started.elapsed().as_millis() as u64,
+            ..Default::default()
+        }
+    }
+
+    fn cost(&self) -> u64 {
+        limits::image_cost(
+            Some(self.images),
+            self.steps.map(|steps| steps as usize),
+            Some(self.height as usize),
+            Some(self.width as usize),
+        )
+    }
+}
+
+/// Aggregated usage of an API key and a model.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct UsageSummary {
+    /// The name of the API key, or `null` for the requests without a key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) key: Option<Option<String>>,
+    /// The model.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) model: Option<String>,
+    /// Number of completed requests.
+    pub(crate) requests: u64,
+    /// Number of images generated.
+    pub(crate) images: u64,
+    /// Number of pixels generated.
+    pub(crate) pixels: u64,
+    /// Cost of the requests, as counted by the rate limits of the API keys.
+    pub(crate) cost: u64,
+    /// Total wall-clock duration of the generations in milliseconds.
+    pub(crate) duration_ms: u64,
+    /// Number of images generated at each resolution, e.g. `512x512`.
+    pub(crate) sizes: BTreeMap<String, u64>,
+}
+impl UsageSummary {
+    fn add(&mut self, record: &UsageRecord) {
+        self.requests += 1;
+        self.images += record.images;
+        self.pixels += record.images * record.height * record.width;
+        self.cost += record.cost();
+        self.duration_ms += record.duration_ms;
+        *self
+            .sizes
+            .entry(format!("{}x{}", record.width, record.height))
+            .or_default() += record.images;
+    }
+
+    fn merge(&mut self, other: &UsageSummary) {
+        self.requests += other.requests;
+        self.images += other.images;
+        self.pixels += other.pixels;
+        self.cost += other.cost;
+        self.duration_ms += other.duration_ms;
+        for (size, images) in other.sizes.iter() {
+            *self.sizes.entry(size.clone()).or_default() += images;
+        }
+    }
+}
+
+/// The usage of a period, as returned by the usage endpoint.
+#[derive(Debug, Serialize)]
+pub(crate) struct UsageReport {
+    /// The object type, which is always `usage`.
+    pub(crate) object: String,
+    /// Unix timestamp of the start of the period, included, rounded down to midnight UTC.
+    pub(crate) from: Option<u64>,
+    /// Unix timestamp of the end of the period, excluded, rounded up to midnight UTC.
+    pub(crate) to: Option<u64>,
+    /// The usage of each API key and model, sorted by key and model.
+    pub(crate) data: Vec<UsageSummary>,
+    /// The usage of every API key and model.
+    pub(crate) total: UsageSummary,
+}
+
+/// Set the file the usage records are appended to. The file is created on the first record.
+pub(crate) fn init_usage(path: PathBuf) -> Result<(), ServerError> {
+    if USAGE_FILE.set(path).is_err() {
+        return Err(ServerError::Operation(
+            "Failed to set USAGE_FILE.".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Seconds since the Unix epoch.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+/// Append the record to the usage file. A failure is logged, as the images are already generated.
+pub(crate) fn record(record: UsageRecord) {
+    let path = match USAGE_FILE.get() {
+        Some(path) => path,
+        None => return,
+    };
+
+    let result = serde_json::to_string(&record)
+        .map_err(|e| e.to_string())
+        .and_then(|line| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", line))
+                .map_err(|e| e.to_string())
+        });
+
+    if let Err(e) = result {
+        // log
+        error!(target: "stdout", "Failed to record the usage in {}. {}", path.display(), e);
+    }
+}
+
+/// Aggregate the records created in `[from, to)` by key and model, keeping only the records of
+/// the given key, if any. The period is widened to whole UTC days, as the records are only kept
+/// as daily usage.
+///
+/// The daily usage is kept in memory, so the usage file is read once and then only for the lines
+/// appended since the previous report.
+pub(crate) fn usage_report(
+    from: Option<u64>,
+    to: Option<u64>,
+    key: Option<&str>,
+) -> Result<UsageReport, ServerError> {
+    let mut cache = records();
+    if let Some(path) = USAGE_FILE.get() {
+        cache.refresh(path).map_err(|e| {
+            ServerError::Operation(format!(
+                "Failed to read the usage file {}. {}",
+                path.display(),
+                e
+            ))
+        })?;
+    }
+
+    Ok(cache.report(
+        from.map(|from| from / DAY),
+        to.map(|to| to.div_ceil(DAY)),
+        key,
+    ))
+}
+
+/// Parse a time of the usage endpoint: a Unix timestamp in seconds, or a `YYYY-MM-DD` date, taken
+/// at midnight UTC, or at the end of the day if `end_of_day` is set, so that a date ending a period
+/// is included.
+pub(crate) fn parse_time(text: &str, end_of_day: bool) -> Option<u64> {
+    let text = text.trim();
+
+    if !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit()) {
+        return text.parse().ok();
+    }
+
+    let mut parts = text.splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: u32 = parts.next()?.parse().ok()?;
+    let day: u32 = parts.next()?.parse().ok()?;
+    if !(1970..=9999).contains(&year) || !(1..=12).contains(&month) || day == 0 {
+        return None;
+    }
+
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if day > days_in_month {
+        return None;
+    }
+
+    let midnight = days_from_civil(year, month, day) as u64 * DAY;
+
+    match end_of_day {
+        true => Some(midnight + DAY),
+        false => Some(midnight),
+    }
+}
+
+/// Days since 1970-01-01 of the date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn days_are_counted_from_the_epoch() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(2024, 2, 29), 19782);
+        assert_eq!(days_from_civil(2026, 9, 1), 20697);
+    }
+
+    #[test]
+    fn dates_and_timestamps_are_parsed() {
+        assert_eq!(parse_time("1788220800", false), Some(1788220800));
+        assert_eq!(parse_time(" 1788220800 ", true), Some(1788220800));
+        assert_eq!(parse_time("2026-09-01", false), Some(1788220800));
+        assert_eq!(parse_time("2026-09-01", true), Some(1788220800 + DAY));
+        assert_eq!(parse_time("2024-02-29", false), Some(1709164800));
+    }
+
+    #[test]
+    fn invalid_dates_are_rejected() {
+        for text in [
+            "",
+            "yesterday",
+            "2026-13-01",
+            "2026-00-10",
+            "2026-04-31",
+            "2023-02-29",
+            "1969-12-31",
+            "2026-09",
+            "-1",
+        ] {
+            assert_eq!(parse_time(text, false), None, "{}", text);
+        }
+    }
+
+    fn record(created: u64, key: &str, images: u64) -> UsageRecord {
+        UsageRecord {
+            created,
+            key: Some(key.to_string()),
+            endpoint: "images.generations".to_string(),
+            model: "sd-v1.4".to_string(),
+            images,
+            height: 512,
+            width: 512,
+            steps: Some(20),
+            duration_ms: 1000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn records_are_kept_as_daily_usage() {
+        let day = parse_time("2026-09-01", false).unwrap();
+        let mut cache = RecordCache::default();
+        for record in [
+            record(day + 60, "design-tools", 2),
+            record(day + 3600, "design-tools", 1),
+            record(day + 7200, "batch", 4),
+            record(day + DAY + 60, "design-tools", 1),
+        ] {
+            cache.add(&record);
+        }
+        assert_eq!(cache.days.len(), 3);
+
+        // the period is widened to the whole first day
+        let report = cache.report(Some(day / DAY), Some((day + 1800).div_ceil(DAY)), None);
+        assert_eq!(report.from, Some(day));
+        assert_eq!(report.to, Some(day + DAY));
+        assert_eq!(report.data.len(), 2);
+        assert_eq!(report.total.requests, 3);
+        assert_eq!(report.total.images, 7);
+        assert_eq!(report.total.sizes["512x512"], 7);
+
+        let report = cache.report(None, None, Some("design-tools"));
+        assert_eq!(report.data.len(), 1);
+        assert_eq!(report.data[0].requests, 3);
+        assert_eq!(report.data[0].images, 4);
+        assert_eq!(report.data[0].duration_ms, 3000);
+    }
+}
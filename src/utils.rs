@@ -1,9 +1,22 @@
 use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub(crate) fn gen_image_id() -> String {
     format!("imgen-{}", uuid::Uuid::new_v4())
 }
 
+pub(crate) fn gen_job_id() -> String {
+    format!("job_{}", uuid::Uuid::new_v4())
+}
+
+/// Return the current Unix timestamp in seconds.
+pub(crate) fn unix_timestamp() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(n) => n.as_secs(),
+        Err(_) => 0,
+    }
+}
+
 #[derive(
     Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, Serialize, Deserialize,
 )]